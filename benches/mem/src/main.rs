@@ -9,7 +9,7 @@ use std::env;
 use std::rc::Rc;
 
 use french_press::*;
-use french_press::alloc::AllocBox;
+use jsrs_common::alloc_box::AllocBox as Nursery;
 use jsrs_common::ast::Exp;
 use jsrs_common::backend::Backend;
 use jsrs_common::types::js_fn::JsFnStruct;
@@ -59,7 +59,7 @@ fn push_pop_no_gc() {
     let mut mgr = init_gc();
     let exp = Exp::Undefined;
     mgr.push_scope(&exp);
-    mgr.pop_scope(None, false);
+    mgr.pop_scope(None, GcYield::Never);
 }
 
 
@@ -67,7 +67,7 @@ fn push_pop_gc() {
     let mut mgr = init_gc();
     let exp = Exp::Undefined;
     mgr.push_scope(&exp);
-    mgr.pop_scope(None, true);
+    mgr.pop_scope(None, GcYield::Forced);
 }
 
 
@@ -104,7 +104,7 @@ fn large_alloc_no_gc_flat_obj() {
                    (JsKey::JsSym("9".to_string()), make_num(9.), None),
                    (JsKey::JsSym("10".to_string()), make_num(10.), None)];
     for _ in 0..100 {
-        let (var, ptr) = make_obj(kvs.clone(), mgr.alloc_box.clone());
+        let (var, ptr) = make_obj(kvs.clone(), mgr.get_alloc_box());
         mgr.alloc(var, Some(ptr)).unwrap();
     }
 }
@@ -148,9 +148,9 @@ fn small_alloc_gc() {
     let kvs = vec![(JsKey::JsSym("0".to_string()), make_num(0.), None)];
     let exp = &Exp::Call(box Exp::Undefined, vec![]);
     mgr.push_scope(&exp);
-    let (var, ptr) = make_obj(kvs.clone(), mgr.alloc_box.clone());
+    let (var, ptr) = make_obj(kvs.clone(), mgr.get_alloc_box());
     mgr.alloc(var, Some(ptr)).unwrap();
-    mgr.pop_scope(None, true).unwrap();
+    mgr.pop_scope(None, GcYield::Forced).unwrap();
 }
 
 
@@ -169,9 +169,9 @@ fn small_flat_heap_alloc_gc() {
                    (JsKey::JsSym("10".to_string()), make_num(10.), None)];
     let exp = Exp::Call(box Exp::Undefined, vec![]);
     mgr.push_scope(&exp);
-    let (var, ptr) = make_obj(kvs.clone(), mgr.alloc_box.clone());
+    let (var, ptr) = make_obj(kvs.clone(), mgr.get_alloc_box());
     mgr.alloc(var, Some(ptr)).unwrap();
-    mgr.pop_scope(None, true).unwrap();
+    mgr.pop_scope(None, GcYield::Forced).unwrap();
 }
 
 
@@ -190,10 +190,10 @@ fn large_flat_alloc_gc() {
                    (JsKey::JsSym("10".to_string()), make_num(10.), None)];
     mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
     for _ in 0..100 {
-        let (var, ptr) = make_obj(kvs.clone(), mgr.alloc_box.clone());
+        let (var, ptr) = make_obj(kvs.clone(), mgr.get_alloc_box());
         mgr.alloc(var, Some(ptr)).unwrap();
     }
-    mgr.pop_scope(None, true).unwrap();
+    mgr.pop_scope(None, GcYield::Forced).unwrap();
 }
 
 
@@ -205,7 +205,7 @@ fn leak_many() {
     let kvs = vec![(key.clone(), var, Some(ptr))];
 
     mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
-    let (var, ptr) = make_obj(kvs.clone(), mgr.alloc_box.clone());
+    let (var, ptr) = make_obj(kvs.clone(), mgr.get_alloc_box());
     let bnd = var.binding.clone();
     mgr.alloc(var.clone(), Some(ptr.clone())).unwrap();
 
@@ -216,13 +216,13 @@ fn leak_many() {
         let (leak_var, leak_ptr) = make_str("test");
         match *&mut ptr {
             Some(JsPtrEnum::JsObj(ref mut obj)) => {
-                obj.add_key(key.clone(), leak_var, Some(leak_ptr), &mut *(mgr.alloc_box.borrow_mut()));
+                obj.add_key(key.clone(), leak_var, Some(leak_ptr), &mut *(mgr.get_alloc_box().borrow_mut()));
             },
             _ => unreachable!()
         }
     }
     mgr.store(var, ptr).unwrap();
-    mgr.pop_scope(None, true).unwrap();
+    mgr.pop_scope(None, GcYield::Forced).unwrap();
 }
 
 fn make_num(i: f64) -> JsVar {
@@ -234,7 +234,8 @@ fn make_str(s: &str) -> (JsVar, JsPtrEnum) {
     (var, JsPtrEnum::JsStr(JsStrStruct::new(s)))
 }
 
-fn make_obj(kvs: Vec<(JsKey, JsVar, Option<JsPtrEnum>)>, heap: Rc<RefCell<AllocBox>>) -> (JsVar, JsPtrEnum) {
+fn make_obj(kvs: Vec<(JsKey, JsVar, Option<JsPtrEnum>)>, nursery: Rc<RefCell<Nursery>>) -> (JsVar, JsPtrEnum) {
     let var = JsVar::new(JsType::JsPtr(JsPtrTag::JsObj));
-    (var, JsPtrEnum::JsObj(JsObjStruct::new(None, "test", kvs, &mut *heap.borrow_mut())))
+    let obj = JsObjStruct::new(None, "test", kvs, &mut *nursery.borrow_mut());
+    (var, JsPtrEnum::JsObj(obj))
 }
@@ -40,7 +40,7 @@ fn push_pop_no_gc(b: &mut Bencher) {
     let mut mgr = init_gc();
     b.iter(|| {
         mgr.push_scope(&UNDEF);
-        mgr.pop_scope(None, false).unwrap();
+        mgr.pop_scope(None, GcYield::Never).unwrap();
     });
 }
 
@@ -49,7 +49,7 @@ fn push_pop_gc(b: &mut Bencher) {
     let mut mgr = init_gc();
     b.iter(|| {
         mgr.push_scope(&UNDEF);
-        mgr.pop_scope(None, true).unwrap();
+        mgr.pop_scope(None, GcYield::Forced).unwrap();
     });
 }
 // ^^ Push & Pop Only ^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^
@@ -60,7 +60,7 @@ fn small_stack_alloc_no_gc(b: &mut Bencher) {
     b.iter(|| {
         mgr.push_scope(&UNDEF);
         mgr.alloc(make_num(0.), None).unwrap();
-        mgr.pop_scope(None, false).unwrap();
+        mgr.pop_scope(None, GcYield::Never).unwrap();
     });
 }
 
@@ -70,7 +70,7 @@ fn small_stack_alloc_gc(b: &mut Bencher) {
     b.iter(|| {
         mgr.push_scope(&UNDEF);
         mgr.alloc(make_num(0.), None).unwrap();
-        mgr.pop_scope(None, true).unwrap();
+        mgr.pop_scope(None, GcYield::Forced).unwrap();
     });
 }
 // ^^ Stack Allocation ^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^
@@ -82,7 +82,7 @@ fn small_str_alloc_no_gc(b: &mut Bencher) {
         mgr.push_scope(&UNDEF);
         let (var, ptr) = make_str("");
         mgr.alloc(var, Some(ptr)).unwrap();
-        mgr.pop_scope(None, false).unwrap();
+        mgr.pop_scope(None, GcYield::Never).unwrap();
     });
 }
 
@@ -93,7 +93,7 @@ fn small_str_alloc_gc(b: &mut Bencher) {
         mgr.push_scope(&UNDEF);
         let (var, ptr) = make_str("");
         mgr.alloc(var, Some(ptr)).unwrap();
-        mgr.pop_scope(None, true).unwrap();
+        mgr.pop_scope(None, GcYield::Forced).unwrap();
     });
 }
 
@@ -104,7 +104,7 @@ fn small_str_alloc_no_gc_2(b: &mut Bencher) {
         mgr.push_scope(&UNDEF);
         let (var, ptr) = make_str("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
         mgr.alloc(var, Some(ptr)).unwrap();
-        mgr.pop_scope(None, false).unwrap();
+        mgr.pop_scope(None, GcYield::Never).unwrap();
     });
 }
 
@@ -115,7 +115,7 @@ fn small_str_alloc_gc_2(b: &mut Bencher) {
         mgr.push_scope(&UNDEF);
         let (var, ptr) = make_str("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
         mgr.alloc(var, Some(ptr)).unwrap();
-        mgr.pop_scope(None, true).unwrap();
+        mgr.pop_scope(None, GcYield::Forced).unwrap();
     });
 }
 
@@ -125,9 +125,9 @@ fn small_obj_alloc_no_gc(b: &mut Bencher) {
     let kvs = vec![(JsKey::JsSym("0".to_string()), make_num(0.), None)];
     b.iter(|| {
         mgr.push_scope(&UNDEF);
-        let (var, ptr) = make_obj(kvs.clone(), mgr.alloc_box.clone());
+        let (var, ptr) = make_obj(kvs.clone(), mgr.get_alloc_box());
         mgr.alloc(var, Some(ptr)).unwrap();
-        mgr.pop_scope(None, false).unwrap();
+        mgr.pop_scope(None, GcYield::Never).unwrap();
     });
 }
 
@@ -137,9 +137,9 @@ fn small_obj_alloc_gc(b: &mut Bencher) {
     let kvs = vec![(JsKey::JsSym("0".to_string()), make_num(0.), None)];
     b.iter(|| {
         mgr.push_scope(&UNDEF);
-        let (var, ptr) = make_obj(kvs.clone(), mgr.alloc_box.clone());
+        let (var, ptr) = make_obj(kvs.clone(), mgr.get_alloc_box());
         mgr.alloc(var, Some(ptr)).unwrap();
-        mgr.pop_scope(None, true).unwrap();
+        mgr.pop_scope(None, GcYield::Forced).unwrap();
     });
 }
 
@@ -159,9 +159,9 @@ fn small_obj_alloc_no_gc_2(b: &mut Bencher) {
                    (JsKey::JsSym("10".to_string()), make_num(10.), None)];
     b.iter(|| {
         mgr.push_scope(&UNDEF);
-        let (var, ptr) = make_obj(kvs.clone(), mgr.alloc_box.clone());
+        let (var, ptr) = make_obj(kvs.clone(), mgr.get_alloc_box());
         mgr.alloc(var, Some(ptr)).unwrap();
-        mgr.pop_scope(None, false).unwrap();
+        mgr.pop_scope(None, GcYield::Never).unwrap();
     });
 }
 
@@ -181,9 +181,9 @@ fn small_obj_alloc_gc_2(b: &mut Bencher) {
                    (JsKey::JsSym("10".to_string()), make_num(10.), None)];
     b.iter(|| {
         mgr.push_scope(&UNDEF);
-        let (var, ptr) = make_obj(kvs.clone(), mgr.alloc_box.clone());
+        let (var, ptr) = make_obj(kvs.clone(), mgr.get_alloc_box());
         mgr.alloc(var, Some(ptr)).unwrap();
-        mgr.pop_scope(None, true).unwrap();
+        mgr.pop_scope(None, GcYield::Forced).unwrap();
     });
 }
 // ^^ Small Heap Allocation ^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^
@@ -205,10 +205,10 @@ fn large_obj_alloc_no_gc(b: &mut Bencher) {
     b.iter(|| {
         mgr.push_scope(&UNDEF);
         for _ in 0..100 {
-            let (var, ptr) = make_obj(kvs.clone(), mgr.alloc_box.clone());
+            let (var, ptr) = make_obj(kvs.clone(), mgr.get_alloc_box());
             mgr.alloc(var, Some(ptr)).unwrap();
         }
-        mgr.pop_scope(None, false).unwrap();
+        mgr.pop_scope(None, GcYield::Never).unwrap();
     });
 }
 
@@ -229,10 +229,10 @@ fn large_obj_alloc_gc(b: &mut Bencher) {
     b.iter(|| {
         mgr.push_scope(&UNDEF);
         for _ in 0..100 {
-            let (var, ptr) = make_obj(kvs.clone(), mgr.alloc_box.clone());
+            let (var, ptr) = make_obj(kvs.clone(), mgr.get_alloc_box());
             mgr.alloc(var, Some(ptr)).unwrap();
         }
-        mgr.pop_scope(None, true).unwrap();
+        mgr.pop_scope(None, GcYield::Forced).unwrap();
     });
 }
 // ^^ Large Flat Heap Allocation ^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^
@@ -244,9 +244,9 @@ fn huge_obj_alloc_no_gc(b: &mut Bencher) {
     let kvs: Vec<_> = (0..100_000).map(|i| (JsKey::JsSym(i.to_string()), make_num(i as f64), None)).collect();
     b.iter(|| {
         mgr.push_scope(&UNDEF);
-        let (var, ptr) = make_obj(kvs.clone(), mgr.alloc_box.clone());
+        let (var, ptr) = make_obj(kvs.clone(), mgr.get_alloc_box());
         mgr.alloc(var, Some(ptr)).unwrap();
-        mgr.pop_scope(None, false).unwrap();
+        mgr.pop_scope(None, GcYield::Never).unwrap();
     });
 }
 
@@ -256,9 +256,9 @@ fn huge_obj_alloc_gc(b: &mut Bencher) {
     let kvs: Vec<_> = (0..100_000).map(|i| (JsKey::JsSym(i.to_string()), make_num(i as f64), None)).collect();
     b.iter(|| {
         mgr.push_scope(&UNDEF);
-        let (var, ptr) = make_obj(kvs.clone(), mgr.alloc_box.clone());
+        let (var, ptr) = make_obj(kvs.clone(), mgr.get_alloc_box());
         mgr.alloc(var, Some(ptr)).unwrap();
-        mgr.pop_scope(None, true).unwrap();
+        mgr.pop_scope(None, GcYield::Forced).unwrap();
     });
 }
 // ^^ Huge Flat Heap Allocation ^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^
@@ -290,6 +290,45 @@ fn deca_load(b: &mut Bencher) {
     });
 }
 
+#[bench]
+fn deca_load_large(b: &mut Bencher) {
+    let mut mgr = init_gc();
+    mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+    let (var, ptr) = make_obj(make_large_kvs(), mgr.get_alloc_box());
+    let bnd = var.binding.clone();
+    mgr.alloc(var, Some(ptr)).unwrap();
+    for _ in 0..10 {
+        mgr.push_scope(&UNDEF);
+    }
+    b.iter(|| {
+        match mgr.load(&bnd).unwrap() {
+            (_, Some(JsPtrEnum::JsObj(obj))) => obj.dict.len(),
+            _ => unreachable!(),
+        }
+    });
+}
+
+#[bench]
+fn deca_load_ref(b: &mut Bencher) {
+    let mut mgr = init_gc();
+    mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+    let (var, ptr) = make_obj(make_large_kvs(), mgr.get_alloc_box());
+    let bnd = var.binding.clone();
+    mgr.alloc(var, Some(ptr)).unwrap();
+    for _ in 0..10 {
+        mgr.push_scope(&UNDEF);
+    }
+    b.iter(|| {
+        let (_, cell) = mgr.load_ref(&bnd).unwrap();
+        cell.unwrap().with(|ptr| {
+            match *ptr {
+                JsPtrEnum::JsObj(ref obj) => obj.dict.len(),
+                _ => unreachable!(),
+            }
+        })
+    });
+}
+
 #[bench]
 fn centi_load(b: &mut Bencher) {
     let mut mgr = init_gc();
@@ -347,7 +386,7 @@ fn tight_loop_10x(b: &mut Bencher) {
         for i in 0..10 {
             mgr.load(&bnd).unwrap();
         }
-        mgr.pop_scope(None, false).unwrap();
+        mgr.pop_scope(None, GcYield::Never).unwrap();
     });
 }
 
@@ -363,7 +402,7 @@ fn tight_loop_100x(b: &mut Bencher) {
         for i in 0..100 {
             mgr.load(&bnd).unwrap();
         }
-        mgr.pop_scope(None, false).unwrap();
+        mgr.pop_scope(None, GcYield::Never).unwrap();
     });
 }
 
@@ -379,7 +418,7 @@ fn tight_loop_1000x(b: &mut Bencher) {
         for i in 0..1000 {
             mgr.load(&bnd).unwrap();
         }
-        mgr.pop_scope(None, false).unwrap();
+        mgr.pop_scope(None, GcYield::Never).unwrap();
     });
 }
 
@@ -399,24 +438,35 @@ fn small_local_store(b: &mut Bencher) {
 #[bench]
 fn large_local_store(b: &mut Bencher) {
     let mut mgr = init_gc();
-    let kvs = vec![(JsKey::JsSym("0".to_string()), make_num(0.), None),
-                   (JsKey::JsSym("1".to_string()), make_num(1.), None),
-                   (JsKey::JsSym("2".to_string()), make_num(2.), None),
-                   (JsKey::JsSym("3".to_string()), make_num(3.), None),
-                   (JsKey::JsSym("4".to_string()), make_num(4.), None),
-                   (JsKey::JsSym("5".to_string()), make_num(5.), None),
-                   (JsKey::JsSym("6".to_string()), make_num(6.), None),
-                   (JsKey::JsSym("7".to_string()), make_num(7.), None),
-                   (JsKey::JsSym("8".to_string()), make_num(8.), None),
-                   (JsKey::JsSym("9".to_string()), make_num(9.), None),
-                   (JsKey::JsSym("10".to_string()), make_num(10.), None)];
     mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
-    let (var, ptr) = make_obj(kvs, mgr.alloc_box.clone());
+    let (var, ptr) = make_obj(make_large_kvs(), mgr.get_alloc_box());
     mgr.alloc(var.clone(), Some(ptr.clone())).unwrap();
     b.iter(|| {
         mgr.store(var.clone(), Some(ptr.clone())).unwrap();
     });
 }
+
+#[bench]
+fn large_local_mutate(b: &mut Bencher) {
+    let mut mgr = init_gc();
+    mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+    let (var, ptr) = make_obj(make_large_kvs(), mgr.get_alloc_box());
+    let bnd = mgr.alloc(var.clone(), Some(ptr)).unwrap();
+    let (_, cell) = mgr.load_ref(&bnd).unwrap();
+    let cell = cell.unwrap();
+    let nursery = mgr.get_alloc_box();
+    b.iter(|| {
+        cell.with_mut(|ptr| {
+            if let JsPtrEnum::JsObj(ref mut obj) = *ptr {
+                obj.add_key(&var.unique,
+                            JsKey::JsSym("0".to_string()),
+                            make_num(0.),
+                            None,
+                            &mut *nursery.borrow_mut());
+            }
+        })
+    });
+}
 // ^^ Variable Store Tests ^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^
 // vv Leak Tests vvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvvv
 #[bench]
@@ -429,7 +479,7 @@ fn leak_many_no_gc(b: &mut Bencher) {
 
     b.iter(|| {
         mgr.push_scope(&UNDEF);
-        let (var, ptr) = make_obj(kvs.clone(), mgr.alloc_box.clone());
+        let (var, ptr) = make_obj(kvs.clone(), mgr.get_alloc_box());
         let bnd = var.binding.clone();
         mgr.alloc(var.clone(), Some(ptr.clone())).unwrap();
 
@@ -440,13 +490,13 @@ fn leak_many_no_gc(b: &mut Bencher) {
             let (leak_var, leak_ptr) = make_str("test");
             match *&mut ptr {
                 Some(JsPtrEnum::JsObj(ref mut obj)) => {
-                    obj.add_key(&var.unique, key.clone(), leak_var, Some(leak_ptr), &mut *(mgr.alloc_box.borrow_mut()));
+                    obj.add_key(&var.unique, key.clone(), leak_var, Some(leak_ptr), &mut *(mgr.get_alloc_box().borrow_mut()));
                 },
                 _ => unreachable!()
             }
         }
         mgr.store(var, ptr).unwrap();
-        mgr.pop_scope(None, false).unwrap();
+        mgr.pop_scope(None, GcYield::Never).unwrap();
     });
 }
 
@@ -460,7 +510,7 @@ fn leak_many_gc(b: &mut Bencher) {
 
     b.iter(|| {
         mgr.push_scope(&UNDEF);
-        let (var, ptr) = make_obj(kvs.clone(), mgr.alloc_box.clone());
+        let (var, ptr) = make_obj(kvs.clone(), mgr.get_alloc_box());
         let bnd = var.binding.clone();
         mgr.alloc(var.clone(), Some(ptr.clone())).unwrap();
 
@@ -471,13 +521,13 @@ fn leak_many_gc(b: &mut Bencher) {
             let (leak_var, leak_ptr) = make_str("test");
             match *&mut ptr {
                 Some(JsPtrEnum::JsObj(ref mut obj)) => {
-                    obj.add_key(&var.unique, key.clone(), leak_var, Some(leak_ptr), &mut *(mgr.alloc_box.borrow_mut()));
+                    obj.add_key(&var.unique, key.clone(), leak_var, Some(leak_ptr), &mut *(mgr.get_alloc_box().borrow_mut()));
                 },
                 _ => unreachable!()
             }
         }
         mgr.store(var, ptr).unwrap();
-        mgr.pop_scope(None, true).unwrap();
+        mgr.pop_scope(None, GcYield::Forced).unwrap();
     });
 }
 // ^^ Leak Tests ^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^
@@ -491,6 +541,12 @@ fn make_str(s: &str) -> (JsVar, JsPtrEnum) {
     (var, JsPtrEnum::JsStr(JsStrStruct::new(s)))
 }
 
+/// An object with a few more properties than usual, to make copying it
+/// noticeable.
+fn make_large_kvs() -> Vec<(JsKey, JsVar, Option<JsPtrEnum>)> {
+    (0..11).map(|i| (JsKey::JsSym(i.to_string()), make_num(i as f64), None)).collect()
+}
+
 fn make_obj(kvs: Vec<(JsKey, JsVar, Option<JsPtrEnum>)>, heap: Rc<RefCell<AllocBox>>) -> (JsVar, JsPtrEnum) {
     let var = JsVar::new(JsType::JsPtr(JsPtrTag::JsObj));
     (var, JsPtrEnum::JsObj(JsObjStruct::new(None, "test", kvs, &mut *heap.borrow_mut())))
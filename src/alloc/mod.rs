@@ -0,0 +1,1235 @@
+use std::cell::RefCell;
+use std::collections::hash_map::HashMap;
+use std::collections::hash_set::HashSet;
+use std::mem;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::usize;
+
+use jsrs_common::alloc_box::AllocBox as Nursery;
+use jsrs_common::gc_error::{GcError, Result};
+use jsrs_common::types::js_var::{JsKey, JsPtrEnum, JsPtrTag, JsType, JsVar};
+use jsrs_common::types::binding::UniqueBinding;
+
+use concurrent::WriteBarrier;
+use host::{HostCell, HostObject};
+
+/// The upper bounds, in estimated bytes, of the size classes that small cells
+/// are sorted into. Anything bigger goes in the large-object space. The
+/// classes are bookkeeping only: every slot holds a `JsPtrEnum`, whose
+/// strings and properties live in allocations of their own, so a slot is the
+/// same size whatever class its space is for. Sorting cells by size keeps
+/// objects that grow and shrink together, and keeps the biggest ones out of
+/// the spaces that compaction has to slide.
+const SIZE_CLASSES: [usize; 4] = [256, 1024, 4096, 16384];
+
+/// The identity of a heap object. Every reference to the object, whether
+/// from a variable or from a property of another object, resolves to the
+/// same id, and two references are to the same object (i.e. `===`) exactly
+/// when their ids are equal. An object keeps its id for as long as it lives,
+/// compaction included.
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ObjectId(usize);
+
+/// Where a cell lives: in a slot of one of the small-object spaces, or in the
+/// large-object space.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum CellAddr {
+    Small(usize, usize),
+    Large,
+}
+
+/// The cells of one size class. New cells take the most recently freed slot
+/// if there is one, and are bumped onto the end of the space otherwise.
+#[derive(Debug, Default)]
+struct Space {
+    slots: Vec<Option<RefCell<JsPtrEnum>>>,
+    free: Vec<usize>,
+}
+
+impl Space {
+    fn alloc(&mut self, ptr: JsPtrEnum) -> usize {
+        let cell = Some(RefCell::new(ptr));
+        if let Some(slot) = self.free.pop() {
+            self.slots[slot] = cell;
+            slot
+        } else {
+            self.slots.push(cell);
+            self.slots.len() - 1
+        }
+    }
+
+    #[inline]
+    fn get(&self, slot: usize) -> Option<&RefCell<JsPtrEnum>> {
+        self.slots.get(slot).and_then(|cell| cell.as_ref())
+    }
+
+    fn free(&mut self, slot: usize) -> Option<JsPtrEnum> {
+        let cell = self.slots[slot].take();
+        self.free.push(slot);
+        cell.map(RefCell::into_inner)
+    }
+}
+
+/// What a mark found live: the references it reached, and the objects it
+/// reached, whether through one of those references or directly.
+#[derive(Debug, Default)]
+pub struct MarkSet {
+    pub refs: HashSet<UniqueBinding>,
+    pub objects: HashSet<ObjectId>,
+}
+
+impl MarkSet {
+    pub fn new() -> MarkSet {
+        MarkSet::default()
+    }
+}
+
+/// Makes the heap operations that can fail fail on demand, so that tests can
+/// check that callers recover from a failure at every step. Outside of tests
+/// it's empty, and never fails anything.
+/// fail_after: How many more operations should succeed before one is made to
+///             fail anyway.
+#[derive(Debug, Default)]
+struct FailureInjector {
+    #[cfg(test)]
+    fail_after: Option<usize>,
+}
+
+impl FailureInjector {
+    #[cfg(test)]
+    fn check(&mut self) -> Result<()> {
+        match self.fail_after {
+            Some(0) => {
+                self.fail_after = None;
+                Err(GcError::PtrAlloc)
+            }
+            Some(n) => {
+                self.fail_after = Some(n - 1);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    #[cfg(not(test))]
+    #[inline]
+    fn check(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The heap. Every heap-allocated value lives in a cell holding one object.
+/// Variables and properties don't own objects; they refer to them by their
+/// own `UniqueBinding`, which the heap resolves to an `ObjectId`. Any number
+/// of references can share an object.
+/// refs: The object every reference resolves to.
+/// cells: Where every allocated object, live or not, can be found.
+/// spaces: One space per size class, for the cells that fit in one.
+/// large: The cells too big for any size class. These are allocated and freed
+///        individually, so they never leave holes in the small-object spaces.
+/// hosts: The host objects attached to heap objects.
+/// roots: The references from outside the heap, i.e. from a variable on
+///        some scope's stack. Objects that are only referenced by other
+///        objects are traced through them.
+/// marked: The references found to be live by the last mark. An object is
+///         live if any reference to it is.
+/// marked_ids: The objects found to be live by the last mark.
+/// mark_horizon: The id the next object allocated had when the heap was
+///               last marked. Objects with lower ids that the mark didn't
+///               find are dead, even if they haven't been swept yet.
+/// unswept_refs: The references that were in the heap when it was last
+///               marked, and haven't been swept yet.
+/// unswept_cells: Likewise for objects.
+/// freed: The number of objects freed since the heap was last marked.
+/// bytes: The estimated size of every object in the heap, by `ptr_size`.
+/// bytes_allocated: The size of every object ever allocated, as it was when
+///                  it was allocated, plus everything objects have grown by
+///                  since.
+/// next_id: The id the next object allocated will get, which is also the
+///          number of objects ever allocated.
+/// failures: Fails operations on demand, in tests.
+/// sweep_pending: Whether the heap has been marked but not swept yet.
+///                References created in between, including those to promoted
+///                cells, are marked as they're created, so that e.g. mutating
+///                an object through a `CellRef` mid-cycle doesn't lose its
+///                new properties.
+/// nursery: Where `JsObjStruct::new` and `JsObjStruct::add_key` put the
+///          properties of objects the interpreter is still building. They're
+///          moved into the heap proper once the object itself is allocated or
+///          stored. The interpreter keeps its nursery in an `Rc`, which can't
+///          be shared with another thread, so the heap has one of its own,
+///          and is lent the interpreter's for each operation that needs it
+///          (see `with_nursery`).
+#[derive(Debug)]
+pub struct AllocBox {
+    refs: HashMap<UniqueBinding, ObjectId>,
+    cells: HashMap<ObjectId, CellAddr>,
+    spaces: Vec<Space>,
+    large: HashMap<ObjectId, RefCell<JsPtrEnum>>,
+    hosts: HashMap<ObjectId, HostCell>,
+    roots: HashSet<UniqueBinding>,
+    marked: HashSet<UniqueBinding>,
+    marked_ids: HashSet<ObjectId>,
+    mark_horizon: usize,
+    unswept_refs: Vec<UniqueBinding>,
+    unswept_cells: Vec<ObjectId>,
+    freed: usize,
+    bytes: usize,
+    bytes_allocated: usize,
+    next_id: usize,
+    failures: FailureInjector,
+    sweep_pending: bool,
+    nursery: Nursery,
+}
+
+impl Default for AllocBox {
+    fn default() -> AllocBox {
+        AllocBox {
+            refs: HashMap::new(),
+            cells: HashMap::new(),
+            spaces: SIZE_CLASSES.iter().map(|_| Space::default()).collect(),
+            large: HashMap::new(),
+            hosts: HashMap::new(),
+            roots: HashSet::new(),
+            marked: HashSet::new(),
+            marked_ids: HashSet::new(),
+            mark_horizon: 0,
+            unswept_refs: Vec::new(),
+            unswept_cells: Vec::new(),
+            freed: 0,
+            bytes: 0,
+            bytes_allocated: 0,
+            next_id: 0,
+            failures: FailureInjector::default(),
+            sweep_pending: false,
+            nursery: Nursery::new(),
+        }
+    }
+}
+
+impl AllocBox {
+    pub fn new() -> AllocBox {
+        AllocBox::default()
+    }
+
+    /// The nursery the heap promotes objects' properties from, when it isn't
+    /// lent one.
+    pub fn nursery_mut(&mut self) -> &mut Nursery {
+        &mut self.nursery
+    }
+
+    /// Run `f` on the heap with the nursery `nursery` holds in place of its
+    /// own, so that allocations and stores promote properties out of it, and
+    /// sweeps sweep it. The nurseries are swapped back before this returns.
+    /// `f` mustn't touch the lent nursery through `nursery` itself.
+    pub fn with_nursery<R, F>(&mut self, nursery: &NurseryHolder, f: F) -> R
+        where F: FnOnce(&mut AllocBox) -> R
+    {
+        nursery.swap_nursery(&mut self.nursery);
+        let result = f(self);
+        nursery.swap_nursery(&mut self.nursery);
+        result
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    /// The estimated size of the heap in bytes: the sum of `ptr_size` over
+    /// every object in it, as its data is now.
+    #[inline]
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// The number of objects ever allocated in the heap, including those
+    /// promoted out of the nursery.
+    #[inline]
+    pub fn objects_allocated(&self) -> usize {
+        self.next_id
+    }
+
+    /// The number of bytes ever allocated in the heap, by `ptr_size`. An
+    /// object growing in place allocates the difference, and one shrinking
+    /// frees it.
+    #[inline]
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    /// Whether a reference resolves to a live object.
+    #[inline]
+    pub fn is_allocated(&self, unique: &UniqueBinding) -> bool {
+        self.object_id(unique).is_some()
+    }
+
+    /// The object a reference resolves to.
+    pub fn object_id(&self, unique: &UniqueBinding) -> Option<ObjectId> {
+        match self.refs.get(unique) {
+            Some(id) if self.cells.contains_key(id) => Some(*id),
+            _ => None,
+        }
+    }
+
+    /// Whether an object is in the heap, and wasn't found dead by a mark
+    /// whose sweep hasn't got to it yet.
+    pub fn is_live(&self, id: ObjectId) -> bool {
+        if !self.cells.contains_key(&id) {
+            return false;
+        }
+        !self.sweep_pending || id.0 >= self.mark_horizon || self.marked_ids.contains(&id)
+    }
+
+    /// The data of a live object.
+    pub fn object(&self, id: ObjectId) -> Option<&RefCell<JsPtrEnum>> {
+        if self.is_live(id) {
+            self.find_object(&id)
+        } else {
+            None
+        }
+    }
+
+    pub fn find_id(&self, unique: &UniqueBinding) -> Option<&RefCell<JsPtrEnum>> {
+        match self.refs.get(unique) {
+            Some(id) => self.find_object(id),
+            None => None,
+        }
+    }
+
+    fn find_object(&self, id: &ObjectId) -> Option<&RefCell<JsPtrEnum>> {
+        match self.cells.get(id) {
+            Some(&CellAddr::Small(class, slot)) => self.spaces[class].get(slot),
+            Some(&CellAddr::Large) => self.large.get(id),
+            None => None,
+        }
+    }
+
+    /// The host object attached to the object a reference resolves to.
+    pub fn host(&self, unique: &UniqueBinding) -> Option<&RefCell<Box<HostObject>>> {
+        match self.object_id(unique) {
+            Some(id) => self.object_host(id),
+            None => None,
+        }
+    }
+
+    /// The host object attached to an object.
+    pub fn object_host(&self, id: ObjectId) -> Option<&RefCell<Box<HostObject>>> {
+        self.hosts.get(&id).map(|host| &host.0)
+    }
+
+    /// Attach a host object to the object a reference resolves to. It's
+    /// traced along with the object's properties, and swept along with it.
+    pub fn attach_host(&mut self, unique: &UniqueBinding, host: Box<HostObject>) -> Result<()> {
+        match self.object_id(unique) {
+            Some(id) if !self.hosts.contains_key(&id) => {
+                self.hosts.insert(id, HostCell::new(host));
+                Ok(())
+            }
+            _ => Err(GcError::PtrAlloc),
+        }
+    }
+
+    /// Add everything reachable from the references in `grey` and the
+    /// objects in `grey_ids` to `marks`. Whatever is already in `marks` isn't
+    /// traced again.
+    pub fn trace(&self,
+                 marks: &mut MarkSet,
+                 grey: Vec<UniqueBinding>,
+                 grey_ids: Vec<ObjectId>) {
+        let mut grey = grey;
+        let mut grey_ids = grey_ids;
+        self.trace_some(marks, &mut grey, &mut grey_ids, usize::MAX);
+    }
+
+    /// Like `trace`, but stop after visiting `budget` references and objects,
+    /// leaving the rest of `grey` and `grey_ids` to be traced by a later
+    /// call. Returns whether there's nothing left to trace.
+    pub fn trace_some(&self,
+                      marks: &mut MarkSet,
+                      grey: &mut Vec<UniqueBinding>,
+                      grey_ids: &mut Vec<ObjectId>,
+                      budget: usize)
+                      -> bool {
+        let mut budget = budget;
+        while budget > 0 {
+            if let Some(unique) = grey.pop() {
+                if marks.refs.insert(unique.clone()) {
+                    grey_ids.extend(self.object_id(&unique));
+                }
+            } else if let Some(id) = grey_ids.pop() {
+                if self.cells.contains_key(&id) && marks.objects.insert(id) {
+                    self.push_object_children(&id, grey, grey_ids);
+                }
+            } else {
+                return true;
+            }
+            budget -= 1;
+        }
+        grey.is_empty() && grey_ids.is_empty()
+    }
+
+    /// Add the references an object's properties hold to `grey`, and the
+    /// objects its host object holds, if it has one, to `grey_ids`.
+    fn push_object_children(&self,
+                            id: &ObjectId,
+                            grey: &mut Vec<UniqueBinding>,
+                            grey_ids: &mut Vec<ObjectId>) {
+        if let Some(cell) = self.find_object(id) {
+            push_children(&*cell.borrow(), grey);
+        }
+        if let Some(host) = self.hosts.get(id) {
+            host.0.borrow().trace(grey_ids);
+        }
+    }
+
+    /// Allocate a new object, and make `unique` a root referring to it.
+    pub fn alloc(&mut self, unique: UniqueBinding, ptr: JsPtrEnum) -> Result<()> {
+        if self.is_allocated(&unique) {
+            return Err(GcError::PtrAlloc);
+        }
+        self.replace(unique, ptr)
+    }
+
+    /// Allocate a new object, and make `unique` a root referring to it,
+    /// whether or not it referred to another object before. That object is
+    /// left to its other references.
+    pub fn replace(&mut self, unique: UniqueBinding, ptr: JsPtrEnum) -> Result<()> {
+        self.failures.check()?;
+        let mut grey = Vec::new();
+        push_children(&ptr, &mut grey);
+        self.promote(grey);
+        if self.sweep_pending {
+            self.marked.insert(unique.clone());
+        }
+        let id = self.place_new(ptr);
+        self.refs.insert(unique.clone(), id);
+        self.roots.insert(unique);
+        Ok(())
+    }
+
+    /// Make `unique` refer to the object `target` refers to, replacing
+    /// whatever it referred to before. Like a newly allocated reference, it
+    /// becomes a root.
+    pub fn alias(&mut self, unique: UniqueBinding, target: &UniqueBinding) -> Result<()> {
+        self.failures.check()?;
+        let id = match self.object_id(target) {
+            Some(id) => id,
+            None => return Err(GcError::PtrAlloc),
+        };
+        if self.sweep_pending {
+            self.marked.insert(unique.clone());
+        }
+        self.refs.insert(unique.clone(), id);
+        self.roots.insert(unique);
+        Ok(())
+    }
+
+    /// Replace the data of the object a reference resolves to. Every other
+    /// reference to the object sees the new data. The reference becomes a
+    /// root again if it had been condemned. If the new data belongs in a
+    /// different size class, the cell moves to that class's space.
+    pub fn update_ptr(&mut self, unique: &UniqueBinding, ptr: JsPtrEnum) -> Result<()> {
+        self.failures.check()?;
+        if self.mutate(unique, move |data| *data = ptr).is_none() {
+            return Err(GcError::PtrAlloc);
+        }
+        self.roots.insert(unique.clone());
+        Ok(())
+    }
+
+    /// Run `f` on the data of the object a reference resolves to, in place,
+    /// and return its result, or `None` if there's no such object.
+    /// Afterwards, any properties `f` added are promoted out of the nursery,
+    /// and the cell moves to another space if it no longer fits its size
+    /// class. Unlike `update_ptr`, this doesn't make the reference a root.
+    pub fn mutate<R, F>(&mut self, unique: &UniqueBinding, f: F) -> Option<R>
+        where F: FnOnce(&mut JsPtrEnum) -> R
+    {
+        let id = match self.object_id(unique) {
+            Some(id) => id,
+            None => return None,
+        };
+        let (result, grey, before, after) = match self.find_object(&id) {
+            Some(cell) => {
+                let mut data = cell.borrow_mut();
+                let before = ptr_size(&*data);
+                let result = f(&mut *data);
+                let mut grey = Vec::new();
+                push_children(&*data, &mut grey);
+                (result, grey, before, ptr_size(&*data))
+            }
+            None => return None,
+        };
+        self.bytes = self.bytes + after - before;
+        if after > before {
+            self.bytes_allocated += after - before;
+        }
+        self.promote(grey);
+        self.refit(id);
+        Some(result)
+    }
+
+    /// Stop treating a reference as a root. Its object stays in the heap for
+    /// as long as other references to it are live.
+    pub fn condemn(&mut self, unique: UniqueBinding) -> Result<()> {
+        if !self.is_allocated(&unique) {
+            return Err(GcError::PtrAlloc);
+        }
+        self.roots.remove(&unique);
+        Ok(())
+    }
+
+    /// Drop a reference altogether, e.g. because its variable now holds a
+    /// primitive. Its object stays in the heap for as long as other
+    /// references to it are live.
+    pub fn release(&mut self, unique: &UniqueBinding) -> Result<()> {
+        if self.refs.remove(unique).is_none() {
+            return Err(GcError::PtrAlloc);
+        }
+        self.roots.remove(unique);
+        Ok(())
+    }
+
+    /// Mark every reference reachable from the roots.
+    pub fn mark_ptrs(&mut self) {
+        self.mark_ptrs_from(Vec::new());
+    }
+
+    /// Mark every reference reachable from the roots or from the objects in
+    /// `extra`, for objects kept alive from outside the heap. Objects in
+    /// `extra` live through the sweep that follows even if no reference
+    /// resolves to them. Those that aren't in the heap are ignored.
+    pub fn mark_ptrs_from(&mut self, extra: Vec<ObjectId>) {
+        let mut marks = MarkSet::new();
+        let roots = self.roots.iter().cloned().collect();
+        self.trace(&mut marks, roots, extra);
+        self.marked = marks.refs;
+        self.marked_ids = marks.objects;
+        self.mark_horizon = self.next_id;
+        self.unswept_refs = self.refs.keys().cloned().collect();
+        self.unswept_cells = self.cells.keys().cloned().collect();
+        self.freed = 0;
+        self.sweep_pending = true;
+    }
+
+    /// Drop every reference the last mark didn't reach, free every object it
+    /// didn't reach, and return the number of objects freed since the mark.
+    pub fn sweep_ptrs(&mut self) -> usize {
+        self.sweep_some(usize::MAX);
+        self.freed
+    }
+
+    /// Sweep up to `budget` of the references and objects that were in the
+    /// heap when it was last marked, and return whether the sweep is
+    /// finished. Those created since the mark are left alone, so the heap
+    /// may be allocated into in between calls.
+    pub fn sweep_some(&mut self, budget: usize) -> bool {
+        let mut budget = budget;
+        while budget > 0 {
+            if let Some(unique) = self.unswept_refs.pop() {
+                if !self.marked.contains(&unique) {
+                    self.refs.remove(&unique);
+                    self.roots.remove(&unique);
+                }
+            } else if let Some(id) = self.unswept_cells.pop() {
+                if !self.marked_ids.contains(&id) {
+                    self.free(id);
+                }
+            } else {
+                break;
+            }
+            budget -= 1;
+        }
+        if !self.unswept_refs.is_empty() || !self.unswept_cells.is_empty() {
+            return false;
+        }
+        self.marked.clear();
+        self.marked_ids.clear();
+        self.sweep_pending = false;
+        // Properties that have been promoted out of the nursery were
+        // condemned there, so this frees them.
+        self.nursery.mark_ptrs();
+        self.nursery.sweep_ptrs();
+        true
+    }
+
+    /// The number of bytes, by `ptr_size`, allocating `ptr` would add to the
+    /// heap: its own, and those of the nursery cells it would promote.
+    pub fn bytes_needed(&self, ptr: &JsPtrEnum) -> usize {
+        let mut seen = HashSet::new();
+        let mut grey = Vec::new();
+        let mut bytes = ptr_size(ptr);
+        push_children(ptr, &mut grey);
+        while let Some(unique) = grey.pop() {
+            if self.refs.contains_key(&unique) || seen.contains(&unique) {
+                continue;
+            }
+            if let Some(cell) = self.nursery.find_id(&unique) {
+                let data = cell.borrow();
+                push_children(&*data, &mut grey);
+                bytes += ptr_size(&*data);
+                seen.insert(unique);
+            }
+        }
+        bytes
+    }
+
+    /// Make the operation after the next `n` that can fail (`alloc`,
+    /// `replace`, `alias` or `update_ptr`) fail, so that tests can check that
+    /// callers recover from failures at every step.
+    #[cfg(test)]
+    pub fn fail_after(&mut self, n: usize) {
+        self.failures.fail_after = Some(n);
+    }
+
+    /// Drop every reference and free every object that isn't in `live`, and
+    /// slide the surviving cells of each small-object space down over the
+    /// holes that leaves, so that the spaces shrink to fit.
+    /// Objects keep their ids, so nothing that refers to them has to change.
+    /// Only the references in `roots` are still roots afterwards. Large
+    /// objects are never moved. Returns the number of objects freed.
+    pub fn compact(&mut self,
+                   live: &MarkSet,
+                   roots: &HashSet<UniqueBinding>)
+                   -> usize {
+        let dead: Vec<_> = self.refs
+                               .keys()
+                               .filter(|unique| !live.refs.contains(*unique))
+                               .cloned()
+                               .collect();
+        for unique in dead {
+            self.refs.remove(&unique);
+        }
+        self.roots = self.refs.keys().filter(|unique| roots.contains(*unique)).cloned().collect();
+        self.marked.clear();
+        self.marked_ids.clear();
+        self.unswept_refs.clear();
+        self.unswept_cells.clear();
+        self.sweep_pending = false;
+        self.freed = 0;
+        let dead: Vec<_> = self.cells
+                               .keys()
+                               .filter(|id| !live.objects.contains(*id))
+                               .cloned()
+                               .collect();
+        for id in dead {
+            self.free(id);
+        }
+
+        let mut owners: Vec<HashMap<usize, ObjectId>> = self.spaces
+                                                            .iter()
+                                                            .map(|_| HashMap::new())
+                                                            .collect();
+        for (&id, addr) in &self.cells {
+            if let CellAddr::Small(class, slot) = *addr {
+                owners[class].insert(slot, id);
+            }
+        }
+        for (class, space) in self.spaces.iter_mut().enumerate() {
+            let mut next = 0;
+            for slot in 0..space.slots.len() {
+                if space.slots[slot].is_none() {
+                    continue;
+                }
+                if slot != next {
+                    space.slots.swap(slot, next);
+                    self.cells.insert(owners[class][&slot], CellAddr::Small(class, next));
+                }
+                next += 1;
+            }
+            space.slots.truncate(next);
+            space.free.clear();
+        }
+        self.freed
+    }
+
+    /// Free an object, running the sweep hook of its host object if it has
+    /// one.
+    fn free(&mut self, id: ObjectId) {
+        if self.remove(id).is_some() {
+            self.freed += 1;
+        }
+        if let Some(host) = self.hosts.remove(&id) {
+            host.sweep();
+        }
+    }
+
+    fn new_id(&mut self) -> ObjectId {
+        self.next_id += 1;
+        ObjectId(self.next_id - 1)
+    }
+
+    /// Allocate a new object, and return its id.
+    fn place_new(&mut self, ptr: JsPtrEnum) -> ObjectId {
+        let id = self.new_id();
+        self.bytes_allocated += ptr_size(&ptr);
+        self.place(id, ptr);
+        id
+    }
+
+    /// Put an object in a new cell, in the space for its size class.
+    fn place(&mut self, id: ObjectId, ptr: JsPtrEnum) {
+        self.bytes += ptr_size(&ptr);
+        let addr = match size_class(&ptr) {
+            Some(class) => CellAddr::Small(class, self.spaces[class].alloc(ptr)),
+            None => {
+                self.large.insert(id, RefCell::new(ptr));
+                CellAddr::Large
+            }
+        };
+        self.cells.insert(id, addr);
+    }
+
+    fn remove(&mut self, id: ObjectId) -> Option<JsPtrEnum> {
+        let data = match self.cells.remove(&id) {
+            Some(CellAddr::Small(class, slot)) => self.spaces[class].free(slot),
+            Some(CellAddr::Large) => self.large.remove(&id).map(RefCell::into_inner),
+            None => None,
+        };
+        if let Some(ref data) = data {
+            self.bytes -= ptr_size(data);
+        }
+        data
+    }
+
+    /// Move an object to the space for its size class, if it isn't there
+    /// already.
+    fn refit(&mut self, id: ObjectId) {
+        let fits = match (self.cells.get(&id), self.find_object(&id)) {
+            (Some(&CellAddr::Small(class, _)), Some(cell)) => {
+                size_class(&*cell.borrow()) == Some(class)
+            }
+            (Some(&CellAddr::Large), Some(cell)) => size_class(&*cell.borrow()).is_none(),
+            _ => true,
+        };
+        if !fits {
+            if let Some(data) = self.remove(id) {
+                self.place(id, data);
+            }
+        }
+    }
+
+    /// Move the nursery cells in `grey`, which an object's properties point
+    /// to, into the heap, along with everything they point to in turn.
+    /// Promoted cells aren't roots: they're only alive for as long as the
+    /// object refers to them.
+    fn promote(&mut self, mut grey: Vec<UniqueBinding>) {
+        while let Some(unique) = grey.pop() {
+            if self.refs.contains_key(&unique) {
+                continue;
+            }
+            let data = match self.nursery.find_id(&unique) {
+                Some(cell) => cell.borrow().clone(),
+                None => continue,
+            };
+            push_children(&data, &mut grey);
+            self.nursery.condemn(unique.clone()).ok();
+            if self.sweep_pending {
+                self.marked.insert(unique.clone());
+            }
+            let id = self.place_new(data);
+            self.refs.insert(unique, id);
+        }
+    }
+}
+
+/// Where a `ScopeManager` keeps the nursery the interpreter builds objects
+/// in, to be lent to the heap with `AllocBox::with_nursery`.
+pub trait NurseryHolder {
+    /// Swap the nursery held with `nursery`.
+    fn swap_nursery(&self, nursery: &mut Nursery);
+}
+
+/// The nursery `Backend::get_alloc_box` hands out to the interpreter.
+impl NurseryHolder for Rc<RefCell<Nursery>> {
+    fn swap_nursery(&self, nursery: &mut Nursery) {
+        mem::swap(&mut *self.borrow_mut(), nursery);
+    }
+}
+
+/// Holds no nursery, leaving the heap to promote properties from its own,
+/// e.g. for a manager that's been made `Send`, whose objects are built in
+/// `AllocBox::nursery_mut`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct OwnNursery;
+
+impl NurseryHolder for OwnNursery {
+    fn swap_nursery(&self, _: &mut Nursery) {}
+}
+
+/// A handle to the cell behind a pointer variable, returned by
+/// `ScopeManager::load_ref`. Its data can be read or mutated in place
+/// through the handle, without the copies `load` and `store` make. The
+/// handle doesn't keep the cell alive: once the cell has been collected,
+/// `with` and `with_mut` return `None`. The heap stays locked while the
+/// closure passed to `with` runs, so it mustn't call back into the
+/// `ScopeManager`.
+/// tag: The type of the cell's data, as every variable referring to it has it.
+/// nursery: The manager's nursery, for `with_mut` to promote from.
+#[derive(Clone, Debug)]
+pub struct CellRef<N = Rc<RefCell<Nursery>>> {
+    heap: Arc<Mutex<AllocBox>>,
+    unique: UniqueBinding,
+    tag: JsPtrTag,
+    barrier: WriteBarrier,
+    nursery: N,
+}
+
+impl<N: NurseryHolder + Clone> CellRef<N> {
+    pub fn new(heap: &Arc<Mutex<AllocBox>>,
+               unique: UniqueBinding,
+               tag: JsPtrTag,
+               barrier: &WriteBarrier,
+               nursery: &N)
+               -> CellRef<N> {
+        CellRef {
+            heap: heap.clone(),
+            unique: unique,
+            tag: tag,
+            barrier: barrier.clone(),
+            nursery: nursery.clone(),
+        }
+    }
+
+    #[inline]
+    pub fn unique(&self) -> &UniqueBinding {
+        &self.unique
+    }
+
+    /// Run `f` on the cell's data.
+    pub fn with<R, F>(&self, f: F) -> Option<R>
+        where F: FnOnce(&JsPtrEnum) -> R
+    {
+        let heap = self.heap.lock().unwrap();
+        if let Some(cell) = heap.find_id(&self.unique) {
+            let data = cell.borrow();
+            return Some(f(&*data));
+        }
+        None
+    }
+
+    /// Run `f` on the cell's data, allowing it to be changed. Properties
+    /// added with `JsObjStruct::add_key` are moved out of the nursery as
+    /// they would be by `store`. The data has to keep its type, e.g. an
+    /// object can't be swapped for a string, since the variables referring
+    /// to the cell are tagged with it. `f` works on a copy of the data, which
+    /// only replaces it if its type is unchanged; otherwise the cell is left
+    /// as it was and this returns `None`, as it does once the cell has been
+    /// collected. A pointer of another type has to be stored through a
+    /// variable instead. The heap isn't locked while `f` runs.
+    pub fn with_mut<R, F>(&self, f: F) -> Option<R>
+        where F: FnOnce(&mut JsPtrEnum) -> R
+    {
+        let mut copy = match self.with(JsPtrEnum::clone) {
+            Some(copy) => copy,
+            None => return None,
+        };
+        let result = f(&mut copy);
+        if !self.tag.eq_ptr_type(&copy) {
+            return None;
+        }
+        let written = {
+            let mut heap = self.heap.lock().unwrap();
+            heap.with_nursery(&self.nursery,
+                              |heap| heap.mutate(&self.unique, move |data| *data = copy))
+        };
+        if written.is_none() {
+            return None;
+        }
+        // The new data may point to cells a background mark hasn't seen, so
+        // it has to be traced again.
+        self.barrier.record(&self.unique);
+        Some(result)
+    }
+}
+
+/// Estimate how many bytes a heap cell's data takes up.
+pub fn ptr_size(ptr: &JsPtrEnum) -> usize {
+    mem::size_of::<JsPtrEnum>() +
+    match *ptr {
+        JsPtrEnum::JsStr(ref s) => s.text.len(),
+        JsPtrEnum::JsObj(ref obj) => {
+            obj.dict.len() * (mem::size_of::<JsKey>() + mem::size_of::<JsVar>())
+        }
+        _ => 0,
+    }
+}
+
+/// The size class a cell's data belongs in, or `None` if it's too big for
+/// any of them.
+fn size_class(ptr: &JsPtrEnum) -> Option<usize> {
+    let size = ptr_size(ptr);
+    SIZE_CLASSES.iter().position(|&limit| size <= limit)
+}
+
+fn push_children(ptr: &JsPtrEnum, grey: &mut Vec<UniqueBinding>) {
+    if let JsPtrEnum::JsObj(ref obj) = *ptr {
+        for var in obj.dict.values() {
+            if let JsType::JsPtr(_) = var.t {
+                grey.push(var.unique.clone());
+            }
+        }
+    }
+}
+
+/// A fresh, shared heap for tests to build scopes and managers on.
+#[cfg(test)]
+pub fn make_alloc_box() -> Arc<Mutex<AllocBox>> {
+    Arc::new(Mutex::new(AllocBox::new()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::mem;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use jsrs_common::alloc_box::AllocBox as Nursery;
+    use jsrs_common::test_utils;
+    use jsrs_common::types::js_var::{JsKey, JsPtrEnum};
+
+    use host::SweepCounter;
+
+    #[test]
+    fn test_alloc() {
+        let mut heap = AllocBox::new();
+        let (x, x_ptr) = test_utils::make_str("x");
+        assert!(heap.alloc(x.unique.clone(), x_ptr.clone()).is_ok());
+        assert!(heap.is_allocated(&x.unique));
+        assert_eq!(heap.len(), 1);
+        assert!(heap.alloc(x.unique.clone(), x_ptr).is_err());
+        assert_eq!(heap.len(), 1);
+    }
+
+    #[test]
+    fn test_update_ptr() {
+        let mut heap = AllocBox::new();
+        let (x, x_ptr) = test_utils::make_str("x");
+        assert!(heap.update_ptr(&x.unique, x_ptr.clone()).is_err());
+        heap.alloc(x.unique.clone(), x_ptr).unwrap();
+        let (_, y_ptr) = test_utils::make_str("y");
+        heap.update_ptr(&x.unique, y_ptr).unwrap();
+        match *heap.find_id(&x.unique).unwrap().borrow() {
+            JsPtrEnum::JsStr(ref s) => assert_eq!(s.text, "y"),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_condemn_mark_sweep() {
+        let mut heap = AllocBox::new();
+        let (x, x_ptr) = test_utils::make_str("x");
+        let (y, y_ptr) = test_utils::make_str("y");
+        heap.alloc(x.unique.clone(), x_ptr).unwrap();
+        heap.alloc(y.unique.clone(), y_ptr.clone()).unwrap();
+        heap.condemn(y.unique.clone()).unwrap();
+        heap.mark_ptrs();
+        heap.sweep_ptrs();
+        assert!(heap.is_allocated(&x.unique));
+        assert!(!heap.is_allocated(&y.unique));
+        assert!(heap.condemn(y.unique.clone()).is_err());
+
+        // Storing into a condemned cell makes it a root again
+        heap.condemn(x.unique.clone()).unwrap();
+        heap.update_ptr(&x.unique, y_ptr).unwrap();
+        heap.mark_ptrs();
+        heap.sweep_ptrs();
+        assert!(heap.is_allocated(&x.unique));
+    }
+
+    #[test]
+    fn test_mark_ptrs_from() {
+        let mut heap = AllocBox::new();
+        let (x, x_ptr) = test_utils::make_str("x");
+        let (y, y_ptr) = test_utils::make_str("y");
+        heap.alloc(x.unique.clone(), x_ptr).unwrap();
+        heap.alloc(y.unique.clone(), y_ptr).unwrap();
+        heap.condemn(x.unique.clone()).unwrap();
+        heap.condemn(y.unique.clone()).unwrap();
+        let x_id = heap.object_id(&x.unique).unwrap();
+        heap.mark_ptrs_from(vec![x_id]);
+        heap.sweep_ptrs();
+        assert_eq!(heap.len(), 1);
+        assert!(heap.is_live(x_id));
+        // The object lives on without any reference resolving to it
+        assert!(!heap.is_allocated(&x.unique));
+        assert!(!heap.is_allocated(&y.unique));
+
+        // Marking from an object doesn't make it a root
+        heap.mark_ptrs();
+        heap.sweep_ptrs();
+        assert!(heap.is_empty());
+    }
+
+    #[test]
+    fn test_promote() {
+        let mut heap = AllocBox::new();
+        let nursery = Rc::new(RefCell::new(Nursery::new()));
+        let (s, s_ptr) = test_utils::make_str("s");
+        let s_unique = s.unique.clone();
+        let kvs = vec![(JsKey::JsSym("s".to_owned()), s, Some(s_ptr))];
+        let (obj, obj_ptr) = test_utils::make_obj(kvs, nursery.clone());
+        heap.with_nursery(&nursery, |heap| heap.alloc(obj.unique.clone(), obj_ptr)).unwrap();
+        assert_eq!(heap.len(), 2);
+        assert!(heap.is_allocated(&s_unique));
+        heap.mark_ptrs();
+        heap.with_nursery(&nursery, AllocBox::sweep_ptrs);
+        assert!(nursery.borrow().is_empty());
+        assert!(heap.nursery_mut().is_empty());
+
+        // The property isn't a root, so it dies with the object
+        heap.condemn(obj.unique).unwrap();
+        heap.mark_ptrs();
+        heap.sweep_ptrs();
+        assert!(heap.is_empty());
+    }
+
+    #[test]
+    fn test_bytes_needed() {
+        let mut heap = AllocBox::new();
+        let nursery = Rc::new(RefCell::new(Nursery::new()));
+        let (s, s_ptr) = test_utils::make_str("s");
+        let s_size = ptr_size(&s_ptr);
+        let kvs = vec![(JsKey::JsSym("s".to_owned()), s, Some(s_ptr))];
+        let (obj, obj_ptr) = test_utils::make_obj(kvs, nursery.clone());
+        let obj_size = ptr_size(&obj_ptr);
+        // Only the lent nursery's cells are counted
+        assert_eq!(heap.bytes_needed(&obj_ptr), obj_size);
+        assert_eq!(heap.with_nursery(&nursery, |heap| heap.bytes_needed(&obj_ptr)),
+                   obj_size + s_size);
+
+        // A failed allocation leaves nothing behind, not even promoted cells
+        heap.fail_after(0);
+        assert!(heap.with_nursery(&nursery, |heap| heap.alloc(obj.unique.clone(), obj_ptr.clone()))
+                    .is_err());
+        assert!(heap.is_empty());
+        assert_eq!(heap.bytes(), 0);
+        heap.with_nursery(&nursery, |heap| heap.alloc(obj.unique.clone(), obj_ptr.clone()))
+            .unwrap();
+        assert_eq!(heap.len(), 2);
+        assert_eq!(heap.bytes(), obj_size + s_size);
+        assert_eq!(heap.with_nursery(&nursery, |heap| heap.bytes_needed(&obj_ptr)),
+                   obj_size);
+
+        // Mutating an object in place changes the size of the heap with it
+        heap.mutate(&obj.unique, |data| {
+            if let JsPtrEnum::JsObj(ref mut obj) = *data {
+                obj.dict.clear();
+            }
+        });
+        let empty_size = ptr_size(&*heap.find_id(&obj.unique).unwrap().borrow());
+        assert_eq!(heap.bytes(), empty_size + s_size);
+        heap.condemn(obj.unique).unwrap();
+        heap.mark_ptrs();
+        heap.sweep_ptrs();
+        assert_eq!(heap.bytes(), 0);
+    }
+
+    #[test]
+    fn test_alias() {
+        let mut heap = AllocBox::new();
+        let (a, a_ptr) = test_utils::make_str("a");
+        let (b, _) = test_utils::make_str("b");
+        heap.alloc(a.unique.clone(), a_ptr).unwrap();
+        assert!(heap.alias(b.unique.clone(), &a.unique).is_ok());
+        assert_eq!(heap.object_id(&a.unique), heap.object_id(&b.unique));
+        assert_eq!(heap.len(), 1);
+
+        // Writes through one reference are seen through the other
+        let (_, c_ptr) = test_utils::make_str("c");
+        heap.update_ptr(&b.unique, c_ptr).unwrap();
+        match *heap.find_id(&a.unique).unwrap().borrow() {
+            JsPtrEnum::JsStr(ref s) => assert_eq!(s.text, "c"),
+            _ => unreachable!(),
+        }
+
+        // The object lives for as long as either reference does
+        heap.condemn(a.unique.clone()).unwrap();
+        heap.mark_ptrs();
+        heap.sweep_ptrs();
+        assert!(!heap.is_allocated(&a.unique));
+        assert!(heap.is_allocated(&b.unique));
+        heap.condemn(b.unique.clone()).unwrap();
+        heap.mark_ptrs();
+        heap.sweep_ptrs();
+        assert!(heap.is_empty());
+        assert!(heap.alias(a.unique.clone(), &b.unique).is_err());
+    }
+
+    #[test]
+    fn test_host_objects() {
+        let mut heap = AllocBox::new();
+        let nursery = Rc::new(RefCell::new(Nursery::new()));
+        let (obj, obj_ptr) = test_utils::make_obj(vec![], nursery);
+        let (s, s_ptr) = test_utils::make_str("s");
+        heap.alloc(obj.unique.clone(), obj_ptr).unwrap();
+        heap.alloc(s.unique.clone(), s_ptr).unwrap();
+        let s_id = heap.object_id(&s.unique).unwrap();
+        heap.condemn(s.unique.clone()).unwrap();
+        let swept = Arc::new(AtomicUsize::new(0));
+        let host = SweepCounter {
+            objects: vec![s_id],
+            swept: swept.clone(),
+        };
+        heap.attach_host(&obj.unique, Box::new(host)).unwrap();
+        let other = SweepCounter {
+            objects: Vec::new(),
+            swept: swept.clone(),
+        };
+        assert!(heap.attach_host(&obj.unique, Box::new(other)).is_err());
+
+        // The string lives for as long as the host object reports it, though
+        // its own reference is gone
+        heap.mark_ptrs();
+        heap.sweep_ptrs();
+        assert_eq!(heap.len(), 2);
+        assert!(heap.is_live(s_id));
+        assert!(!heap.is_allocated(&s.unique));
+
+        // Compaction keeps the host object of a live object
+        let mut live = MarkSet::new();
+        heap.trace(&mut live, vec![obj.unique.clone()], Vec::new());
+        assert!(live.objects.contains(&s_id));
+        let roots = vec![obj.unique.clone()].into_iter().collect();
+        heap.compact(&live, &roots);
+        assert!(heap.host(&obj.unique).is_some());
+        assert!(heap.is_live(s_id));
+        assert_eq!(swept.load(Ordering::SeqCst), 0);
+
+        heap.condemn(obj.unique.clone()).unwrap();
+        heap.mark_ptrs();
+        heap.sweep_ptrs();
+        assert!(heap.is_empty());
+        assert_eq!(swept.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut heap = AllocBox::new();
+        let (x, x_ptr) = test_utils::make_str("x");
+        let (a, a_ptr) = test_utils::make_str("a");
+        let (b, _) = test_utils::make_str("b");
+        heap.alloc(x.unique.clone(), x_ptr).unwrap();
+        heap.alloc(a.unique.clone(), a_ptr).unwrap();
+        heap.alias(b.unique.clone(), &a.unique).unwrap();
+        let id = heap.object_id(&a.unique);
+        assert_eq!(heap.spaces[0].slots.len(), 2);
+
+        let mut live = MarkSet::new();
+        heap.trace(&mut live, vec![a.unique.clone(), b.unique.clone()], Vec::new());
+        let roots = vec![a.unique.clone()].into_iter().collect();
+        heap.compact(&live, &roots);
+        assert_eq!(heap.len(), 1);
+        assert_eq!(heap.object_id(&a.unique), id);
+        assert_eq!(heap.object_id(&b.unique), id);
+        // The survivor slid down into the dead object's slot
+        assert_eq!(heap.spaces[0].slots.len(), 1);
+        assert!(heap.spaces[0].free.is_empty());
+        match *heap.find_id(&a.unique).unwrap().borrow() {
+            JsPtrEnum::JsStr(ref s) => assert_eq!(s.text, "a"),
+            _ => unreachable!(),
+        }
+
+        // `b` wasn't a root, so it's dropped by the next sweep
+        heap.mark_ptrs();
+        heap.sweep_ptrs();
+        assert!(heap.is_allocated(&a.unique));
+        assert!(!heap.is_allocated(&b.unique));
+    }
+
+    #[test]
+    fn test_mutate() {
+        let mut heap = AllocBox::new();
+        let nursery = Rc::new(RefCell::new(Nursery::new()));
+        let (obj, obj_ptr) = test_utils::make_obj(vec![], nursery.clone());
+        heap.alloc(obj.unique.clone(), obj_ptr).unwrap();
+        let add_str = |heap: &mut AllocBox, key: &str| {
+            let (s, s_ptr) = test_utils::make_str(key);
+            let s_unique = s.unique.clone();
+            let mut data = heap.find_id(&obj.unique).unwrap().borrow().clone();
+            if let JsPtrEnum::JsObj(ref mut obj_struct) = data {
+                obj_struct.add_key(&obj.unique,
+                                   JsKey::JsSym(key.to_owned()),
+                                   s,
+                                   Some(s_ptr),
+                                   &mut *nursery.borrow_mut());
+            }
+            heap.with_nursery(&nursery,
+                              |heap| heap.mutate(&obj.unique, move |old| *old = data))
+                .unwrap();
+            s_unique
+        };
+
+        // Properties added in place are promoted out of the nursery
+        let a = add_str(&mut heap, "a");
+        assert!(heap.is_allocated(&a));
+        assert_eq!(heap.len(), 2);
+
+        // Ones added between a mark and its sweep survive the sweep
+        heap.mark_ptrs();
+        let b = add_str(&mut heap, "b");
+        heap.sweep_ptrs();
+        assert!(heap.is_allocated(&a));
+        assert!(heap.is_allocated(&b));
+
+        let (x, _) = test_utils::make_str("x");
+        assert_eq!(heap.mutate(&x.unique, |_| ()), None);
+    }
+
+    #[test]
+    fn test_ptr_size() {
+        let (_, short) = test_utils::make_str("");
+        let (_, long) = test_utils::make_str("test");
+        assert_eq!(ptr_size(&short), mem::size_of::<JsPtrEnum>());
+        assert_eq!(ptr_size(&long), ptr_size(&short) + 4);
+    }
+
+    #[test]
+    fn test_size_classes() {
+        let mut heap = AllocBox::new();
+        let (x, x_ptr) = test_utils::make_str("x");
+        let (y, y_ptr) = test_utils::make_str("y");
+        let class = size_class(&x_ptr).unwrap();
+        heap.alloc(x.unique.clone(), x_ptr).unwrap();
+        heap.alloc(y.unique.clone(), y_ptr).unwrap();
+        assert_eq!(heap.cells[&heap.refs[&x.unique]], CellAddr::Small(class, 0));
+        assert_eq!(heap.cells[&heap.refs[&y.unique]], CellAddr::Small(class, 1));
+
+        // Freed slots are reused before the space grows
+        heap.condemn(x.unique.clone()).unwrap();
+        heap.mark_ptrs();
+        heap.sweep_ptrs();
+        let (z, z_ptr) = test_utils::make_str("z");
+        heap.alloc(z.unique.clone(), z_ptr).unwrap();
+        assert_eq!(heap.cells[&heap.refs[&z.unique]], CellAddr::Small(class, 0));
+        assert_eq!(heap.spaces[class].slots.len(), 2);
+
+        // Growing a cell past its size class moves it to the large-object space
+        let huge: String = (0..SIZE_CLASSES[SIZE_CLASSES.len() - 1]).map(|_| 'a').collect();
+        let (_, huge_ptr) = test_utils::make_str(&huge);
+        heap.update_ptr(&y.unique, huge_ptr).unwrap();
+        assert_eq!(heap.cells[&heap.refs[&y.unique]], CellAddr::Large);
+        assert_eq!(heap.spaces[class].free, vec![1]);
+        match *heap.find_id(&y.unique).unwrap().borrow() {
+            JsPtrEnum::JsStr(ref s) => assert_eq!(s.text.len(), huge.len()),
+            _ => unreachable!(),
+        }
+        heap.condemn(y.unique.clone()).unwrap();
+        heap.mark_ptrs();
+        heap.sweep_ptrs();
+        assert!(heap.large.is_empty());
+        assert_eq!(heap.len(), 1);
+    }
+}
@@ -0,0 +1,164 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use jsrs_common::types::binding::UniqueBinding;
+
+use alloc::{AllocBox, MarkSet, ObjectId};
+
+/// Logs the heap cells written by `Scope::update_var` and allocated by
+/// `Scope::push_var` while a background mark is running. The marking thread
+/// may already have traced a cell by the time it's written, so these are the
+/// cells it may have missed, and which have to be traced again before
+/// sweeping. Every scope of a `ScopeManager` shares the same barrier.
+#[derive(Clone, Debug, Default)]
+pub struct WriteBarrier(Arc<Mutex<Option<Vec<UniqueBinding>>>>);
+
+impl WriteBarrier {
+    pub fn new() -> WriteBarrier {
+        WriteBarrier::default()
+    }
+
+    #[inline]
+    pub fn is_active(&self) -> bool {
+        self.0.lock().unwrap().is_some()
+    }
+
+    /// Start logging writes.
+    pub fn activate(&self) {
+        *self.0.lock().unwrap() = Some(Vec::new());
+    }
+
+    /// Stop logging writes, and return the cells written since the barrier
+    /// was activated.
+    pub fn take(&self) -> Vec<UniqueBinding> {
+        self.0.lock().unwrap().take().unwrap_or_else(Vec::new)
+    }
+
+    #[inline]
+    pub fn record(&self, unique: &UniqueBinding) {
+        if let Some(ref mut log) = *self.0.lock().unwrap() {
+            log.push(unique.clone());
+        }
+    }
+}
+
+/// The number of references and objects a background mark traces each
+/// time it locks the heap. The interpreter waits for at most one batch
+/// whenever it needs the heap itself.
+pub const MARK_BATCH: usize = 256;
+
+/// A mark running on its own thread, against the heap the interpreter is
+/// still using. It only holds the heap's lock for a batch at a time, and
+/// sees writes as they happen, but may have already traced an object by the
+/// time something is written into it. The write barrier logs every such
+/// write, so that the cells written can be traced again once it's finished.
+/// start: When the mark was started, for timing the whole cycle.
+pub struct BackgroundMark {
+    handle: JoinHandle<MarkSet>,
+    cancelled: Arc<AtomicBool>,
+    pub start: Instant,
+}
+
+impl BackgroundMark {
+    /// Start marking everything reachable from the references in `roots` and
+    /// the objects in `root_ids`.
+    pub fn start(heap: &Arc<Mutex<AllocBox>>,
+                 roots: Vec<UniqueBinding>,
+                 root_ids: Vec<ObjectId>)
+                 -> BackgroundMark {
+        let heap = heap.clone();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let stop = cancelled.clone();
+        let handle = thread::spawn(move || {
+            let mut marks = MarkSet::new();
+            let mut grey = roots;
+            let mut grey_ids = root_ids;
+            while !stop.load(Ordering::SeqCst) {
+                let locked = heap.lock().unwrap();
+                if locked.trace_some(&mut marks, &mut grey, &mut grey_ids, MARK_BATCH) {
+                    break;
+                }
+            }
+            marks
+        });
+        BackgroundMark {
+            handle: handle,
+            cancelled: cancelled,
+            start: Instant::now(),
+        }
+    }
+
+    /// Wait for the mark to finish, and return what it marked.
+    pub fn join(self) -> MarkSet {
+        self.handle.join().expect("Background marking thread panicked")
+    }
+
+    /// Stop the mark after the batch it's on, and wait for its thread to
+    /// exit. The heap mustn't be locked by the caller.
+    pub fn cancel(self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.handle.join().ok();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use jsrs_common::alloc_box::AllocBox as Nursery;
+    use jsrs_common::test_utils;
+    use jsrs_common::types::js_var::JsKey;
+
+    #[test]
+    fn test_write_barrier() {
+        let barrier = WriteBarrier::new();
+        let shared = barrier.clone();
+        let x = test_utils::make_num(1.);
+        barrier.record(&x.unique);
+        assert!(!shared.is_active());
+        barrier.activate();
+        shared.record(&x.unique);
+        assert_eq!(barrier.take(), vec![x.unique]);
+        assert!(!shared.is_active());
+    }
+
+    #[test]
+    fn test_mark() {
+        let heap = alloc::make_alloc_box();
+        let nursery = Rc::new(RefCell::new(Nursery::new()));
+        let (s, s_ptr) = test_utils::make_str("s");
+        let s_unique = s.unique.clone();
+        let kvs = vec![(JsKey::JsSym("s".to_owned()), s, Some(s_ptr))];
+        let (obj, obj_ptr) = test_utils::make_obj(kvs, nursery.clone());
+        heap.lock()
+            .unwrap()
+            .with_nursery(&nursery, |heap| heap.alloc(obj.unique.clone(), obj_ptr))
+            .unwrap();
+        let (x, x_ptr) = test_utils::make_str("x");
+        heap.lock().unwrap().alloc(x.unique.clone(), x_ptr).unwrap();
+        let x_id = heap.lock().unwrap().object_id(&x.unique).unwrap();
+
+        let marked = BackgroundMark::start(&heap, vec![obj.unique.clone()], Vec::new()).join();
+        assert!(marked.refs.contains(&obj.unique));
+        assert!(marked.refs.contains(&s_unique));
+        assert!(!marked.refs.contains(&x.unique));
+
+        // An object held by a root provider is reached with no reference to
+        // it
+        let marked = BackgroundMark::start(&heap, Vec::new(), vec![x_id]).join();
+        assert!(marked.objects.contains(&x_id));
+        assert!(!marked.refs.contains(&x.unique));
+
+        // The heap is left for the interpreter once the mark is cancelled
+        BackgroundMark::start(&heap, vec![obj.unique.clone()], Vec::new()).cancel();
+        assert_eq!(heap.lock().unwrap().len(), 3);
+    }
+}
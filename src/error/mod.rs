@@ -0,0 +1,35 @@
+use std::result;
+
+use jsrs_common::gc_error::GcError;
+
+/// The ways a `ScopeManager` operation can fail. `GcError` comes from
+/// js.rs-common, which knows nothing about the heap limit this crate
+/// enforces, so running out of memory is reported alongside it.
+#[derive(Debug)]
+pub enum HeapError {
+    /// The heap is at its limit, and an emergency collection couldn't free
+    /// enough of it.
+    OutOfMemory,
+    Gc(GcError),
+}
+
+pub type HeapResult<T> = result::Result<T, HeapError>;
+
+impl From<GcError> for HeapError {
+    fn from(e: GcError) -> HeapError {
+        HeapError::Gc(e)
+    }
+}
+
+/// The `Backend` methods can only fail with a `GcError`, and a variant for
+/// running out of memory can't be added to it from here, so they report it
+/// as a failed pointer allocation. `ScopeManager::try_alloc` and `try_store`
+/// are what they're built on, and tell the two apart.
+impl From<HeapError> for GcError {
+    fn from(e: HeapError) -> GcError {
+        match e {
+            HeapError::OutOfMemory => GcError::PtrAlloc,
+            HeapError::Gc(e) => e,
+        }
+    }
+}
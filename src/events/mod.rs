@@ -0,0 +1,166 @@
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use jsrs_common::types::binding::UniqueBinding;
+
+use gc::{CollectionKind, GcYield};
+
+/// Something that happened in a `ScopeManager` that's relevant to the
+/// collector.
+#[derive(Clone, Debug)]
+pub enum GcEvent {
+    ScopePush { depth: usize },
+    ScopePop { depth: usize, gc_yield: GcYield },
+    /// A pointer was allocated in the heap.
+    Alloc { unique: UniqueBinding, bytes: usize },
+    /// A store created, replaced or removed a root.
+    Store { unique: UniqueBinding, is_ptr: bool },
+    CollectionStart { kind: CollectionKind, live: usize },
+    CollectionEnd {
+        kind: CollectionKind,
+        freed: usize,
+        live: usize,
+        duration: Duration,
+    },
+}
+
+/// Writes `GcEvent`s to a sink as JSON Lines: one JSON object per line, each
+/// with the event's name and the number of microseconds since the log was
+/// created.
+pub struct EventLog {
+    sink: Box<Write + Send>,
+    start: Instant,
+}
+
+impl EventLog {
+    pub fn new(sink: Box<Write + Send>) -> EventLog {
+        EventLog {
+            sink: sink,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn record(&mut self, event: &GcEvent) -> io::Result<()> {
+        let mut line = format!("{{\"t_us\":{}", micros(self.start.elapsed()));
+        match *event {
+            GcEvent::ScopePush { depth } => {
+                line.push_str(&format!(",\"event\":\"scope_push\",\"depth\":{}", depth));
+            }
+            GcEvent::ScopePop { depth, gc_yield } => {
+                line.push_str(&format!(",\"event\":\"scope_pop\",\"depth\":{},\"gc_yield\":{}",
+                                       depth,
+                                       json_str(&format!("{:?}", gc_yield))));
+            }
+            GcEvent::Alloc { ref unique, bytes } => {
+                line.push_str(&format!(",\"event\":\"alloc\",\"unique\":{},\"bytes\":{}",
+                                       json_str(&format!("{:?}", unique)),
+                                       bytes));
+            }
+            GcEvent::Store { ref unique, is_ptr } => {
+                line.push_str(&format!(",\"event\":\"store\",\"unique\":{},\"is_ptr\":{}",
+                                       json_str(&format!("{:?}", unique)),
+                                       is_ptr));
+            }
+            GcEvent::CollectionStart { kind, live } => {
+                line.push_str(&format!(",\"event\":\"collection_start\",\"kind\":{},\"live\":{}",
+                                       json_str(&format!("{:?}", kind)),
+                                       live));
+            }
+            GcEvent::CollectionEnd { kind, freed, live, duration } => {
+                line.push_str(&format!(",\"event\":\"collection_end\",\"kind\":{},\"freed\":{},\
+                                        \"live\":{},\"duration_us\":{}",
+                                       json_str(&format!("{:?}", kind)),
+                                       freed,
+                                       live,
+                                       micros(duration)));
+            }
+        }
+        line.push_str("}\n");
+        self.sink.write_all(line.as_bytes())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.sink.flush()
+    }
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + (d.subsec_nanos() / 1_000) as u64
+}
+
+/// Quote and escape a string for use as a JSON value.
+pub fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// An in-memory sink that tests can read back after handing it to a log.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct SharedBuf(pub ::std::sync::Arc<::std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use gc::{CollectionKind, GcYield};
+
+    #[test]
+    fn test_json_str() {
+        assert_eq!(json_str("a"), "\"a\"");
+        assert_eq!(json_str("\"\\\n"), "\"\\\"\\\\\\n\"");
+        assert_eq!(json_str("\u{1}"), "\"\\u0001\"");
+    }
+
+    #[test]
+    fn test_record() {
+        let buf = SharedBuf(Arc::new(Mutex::new(Vec::new())));
+        let mut log = EventLog::new(Box::new(buf.clone()));
+        log.record(&GcEvent::ScopePush { depth: 2 }).unwrap();
+        log.record(&GcEvent::ScopePop {
+               depth: 1,
+               gc_yield: GcYield::Forced,
+           })
+           .unwrap();
+        log.record(&GcEvent::CollectionStart {
+               kind: CollectionKind::Major,
+               live: 3,
+           })
+           .unwrap();
+
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("{\"t_us\":"));
+        assert!(lines[0].ends_with(",\"event\":\"scope_push\",\"depth\":2}"));
+        assert!(lines[1].ends_with(",\"event\":\"scope_pop\",\"depth\":1,\"gc_yield\":\"Forced\"}"));
+        assert!(lines[2].ends_with(",\"event\":\"collection_start\",\"kind\":\"Major\",\"live\":3}"));
+    }
+}
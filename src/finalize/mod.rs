@@ -0,0 +1,223 @@
+use std::collections::hash_map::HashMap;
+
+use jsrs_common::gc_error::{GcError, Result};
+use jsrs_common::types::js_var::{JsPtrEnum, JsVar};
+use jsrs_common::types::binding::UniqueBinding;
+
+use alloc::{AllocBox, ObjectId};
+use weak::{HeldValue, WeakRef};
+
+/// Cleanup work for an object the collector found dead. Jobs are queued while
+/// sweeping and handed to the embedder once the collection is over, so no
+/// script or host code ever runs in the middle of a sweep.
+/// target: The collected object. It can no longer be dereferenced.
+/// held: The value given when the finalizer was registered, e.g. the held
+///       value of a `FinalizationRegistry`, or a token identifying host state.
+#[derive(Clone, Debug)]
+pub struct FinalizationJob {
+    pub target: WeakRef,
+    pub held: JsVar,
+    pub held_ptr: Option<JsPtrEnum>,
+}
+
+/// Finalizers registered against heap objects. Held values are kept alive by
+/// the registry until their job has been taken by the embedder.
+#[derive(Debug, Default)]
+pub struct FinalizationRegistry {
+    registrations: HashMap<ObjectId, Vec<HeldValue>>,
+    queued: Vec<(ObjectId, HeldValue)>,
+}
+
+impl FinalizationRegistry {
+    pub fn new() -> FinalizationRegistry {
+        FinalizationRegistry {
+            registrations: HashMap::new(),
+            queued: Vec::new(),
+        }
+    }
+
+    /// The number of jobs waiting to be taken.
+    #[inline]
+    pub fn pending(&self) -> usize {
+        self.queued.len()
+    }
+
+    /// Register a finalizer for a live heap object. The registry holds on to
+    /// `held` through a heap reference of its own, so the value lives until
+    /// its job has been taken, whatever becomes of the variable it came from.
+    /// Returns that reference, if `held` is a pointer.
+    pub fn register(&mut self,
+                    target: &WeakRef,
+                    held: JsVar,
+                    ptr: Option<JsPtrEnum>,
+                    heap: &mut AllocBox)
+                    -> Result<Option<UniqueBinding>> {
+        if !heap.is_live(target.target()) {
+            return Err(GcError::PtrAlloc);
+        }
+        let held = HeldValue::new(held, ptr, heap)?;
+        let unique = held.ptr_unique().cloned();
+        self.registrations.entry(target.target()).or_insert_with(Vec::new).push(held);
+        Ok(unique)
+    }
+
+    /// Remove every finalizer registered for an object, returning whether
+    /// there were any.
+    pub fn unregister(&mut self, target: &WeakRef, heap: &mut AllocBox) -> bool {
+        if let Some(held) = self.registrations.remove(&target.target()) {
+            for value in held {
+                value.release(heap);
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Called after a sweep. Queue a job for every registration whose target
+    /// is no longer in the heap.
+    pub fn queue_dead(&mut self, heap: &AllocBox) {
+        let dead: Vec<_> = self.registrations
+                               .keys()
+                               .filter(|&&target| !heap.is_live(target))
+                               .cloned()
+                               .collect();
+        for target in dead {
+            if let Some(held) = self.registrations.remove(&target) {
+                for value in held {
+                    self.queued.push((target, value));
+                }
+            }
+        }
+    }
+
+    /// Hand every queued job to the embedder, releasing the held values.
+    pub fn take_jobs(&mut self, heap: &mut AllocBox) -> Vec<FinalizationJob> {
+        let mut jobs = Vec::with_capacity(self.queued.len());
+        for (target, value) in self.queued.drain(..) {
+            let (held, held_ptr) = value.copy(heap);
+            value.release(heap);
+            jobs.push(FinalizationJob {
+                target: WeakRef::new(target),
+                held: held,
+                held_ptr: held_ptr,
+            });
+        }
+        jobs
+    }
+
+    /// The heap cells of every value the registry is holding on to.
+    pub fn roots(&self) -> Vec<UniqueBinding> {
+        self.registrations
+            .values()
+            .flat_map(|held| held.iter())
+            .chain(self.queued.iter().map(|&(_, ref value)| value))
+            .filter_map(|value| value.ptr_unique().cloned())
+            .collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc;
+
+    use jsrs_common::gc_error::GcError;
+    use jsrs_common::test_utils;
+    use jsrs_common::types::js_var::JsType;
+
+    use weak::WeakRef;
+
+    #[test]
+    fn test_register_fail() {
+        let heap = alloc::make_alloc_box();
+        let (var, ptr) = test_utils::make_str("dead");
+        heap.lock().unwrap().alloc(var.unique.clone(), ptr).unwrap();
+        let target = WeakRef::new(heap.lock().unwrap().object_id(&var.unique).unwrap());
+        heap.lock().unwrap().condemn(var.unique).unwrap();
+        heap.lock().unwrap().mark_ptrs();
+        heap.lock().unwrap().sweep_ptrs();
+        let mut registry = FinalizationRegistry::new();
+        let res = registry.register(&target,
+                                    test_utils::make_num(1.),
+                                    None,
+                                    &mut *heap.lock().unwrap());
+        assert!(matches!(res, Err(GcError::PtrAlloc)));
+    }
+
+    #[test]
+    fn test_queue_dead() {
+        let heap = alloc::make_alloc_box();
+        let (var, ptr) = test_utils::make_str("target");
+        heap.lock().unwrap().alloc(var.unique.clone(), ptr).unwrap();
+        let target = WeakRef::new(heap.lock().unwrap().object_id(&var.unique).unwrap());
+
+        let mut registry = FinalizationRegistry::new();
+        let (held, held_ptr) = test_utils::make_str("held");
+        registry.register(&target, held, Some(held_ptr), &mut *heap.lock().unwrap()).unwrap();
+        assert_eq!(registry.roots().len(), 1);
+
+        // Nothing is queued while the target is alive
+        registry.queue_dead(&*heap.lock().unwrap());
+        assert_eq!(registry.pending(), 0);
+
+        heap.lock().unwrap().condemn(var.unique).unwrap();
+        heap.lock().unwrap().mark_ptrs();
+        heap.lock().unwrap().sweep_ptrs();
+        registry.queue_dead(&*heap.lock().unwrap());
+        assert_eq!(registry.pending(), 1);
+
+        let jobs = registry.take_jobs(&mut *heap.lock().unwrap());
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].target, target);
+        assert!(matches!(jobs[0].held.t, JsType::JsPtr(_)));
+        assert!(jobs[0].held_ptr.is_some());
+        assert_eq!(registry.pending(), 0);
+        assert!(registry.roots().is_empty());
+    }
+
+    #[test]
+    fn test_register_holds_allocated_value() {
+        let heap = alloc::make_alloc_box();
+        let (var, ptr) = test_utils::make_str("target");
+        heap.lock().unwrap().alloc(var.unique.clone(), ptr).unwrap();
+        let target = WeakRef::new(heap.lock().unwrap().object_id(&var.unique).unwrap());
+        let (held, held_ptr) = test_utils::make_str("held");
+        heap.lock().unwrap().alloc(held.unique.clone(), held_ptr).unwrap();
+
+        let mut registry = FinalizationRegistry::new();
+        let unique = registry.register(&target, held.clone(), None, &mut *heap.lock().unwrap())
+                             .unwrap();
+        // The registry holds the value through a reference of its own
+        assert!(unique.is_some() && unique != Some(held.unique.clone()));
+        // The held value's variable lets go of it, and the target dies
+        heap.lock().unwrap().release(&held.unique).unwrap();
+        heap.lock().unwrap().condemn(var.unique).unwrap();
+        heap.lock().unwrap().mark_ptrs();
+        heap.lock().unwrap().sweep_ptrs();
+        registry.queue_dead(&*heap.lock().unwrap());
+
+        let jobs = registry.take_jobs(&mut *heap.lock().unwrap());
+        assert_eq!(jobs.len(), 1);
+        assert!(jobs[0].held_ptr.is_some());
+        heap.lock().unwrap().mark_ptrs();
+        heap.lock().unwrap().sweep_ptrs();
+        assert!(heap.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unregister() {
+        let heap = alloc::make_alloc_box();
+        let (var, ptr) = test_utils::make_str("target");
+        heap.lock().unwrap().alloc(var.unique.clone(), ptr).unwrap();
+        let target = WeakRef::new(heap.lock().unwrap().object_id(&var.unique).unwrap());
+
+        let mut registry = FinalizationRegistry::new();
+        registry.register(&target, test_utils::make_num(1.), None, &mut *heap.lock().unwrap())
+                .unwrap();
+        assert!(registry.unregister(&target, &mut *heap.lock().unwrap()));
+        assert!(!registry.unregister(&target, &mut *heap.lock().unwrap()));
+    }
+}
@@ -0,0 +1,209 @@
+/// How thorough a collection should be. The heap isn't generational, so
+/// every kind marks and sweeps all of it; they differ only in what else they
+/// do. A `Minor` collection is no cheaper than a `Major` one, and may free
+/// less.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CollectionKind {
+    /// Mark and sweep the whole heap, as a major collection does, but leave
+    /// ephemeron tables alone, so the values of entries whose keys have died
+    /// are kept alive until the next major collection. There's no young
+    /// generation to restrict it to.
+    Minor,
+    /// Mark and sweep the heap, clearing ephemerons whose keys have died, and
+    /// compact afterwards if compaction has been turned on.
+    Major,
+    /// A major collection that always ends by compacting the heap.
+    Full,
+}
+
+impl CollectionKind {
+    #[inline]
+    pub fn clears_ephemerons(&self) -> bool {
+        *self != CollectionKind::Minor
+    }
+}
+
+/// What a collection accomplished.
+/// freed: The number of heap cells that were reclaimed.
+/// live: The number of heap cells left afterwards.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct CollectionResult {
+    pub freed: usize,
+    pub live: usize,
+}
+
+/// Where the current collection cycle has got to. Cycles can be run a step
+/// at a time, with the interpreter free to run in between.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum GcPhase {
+    /// No collection is in progress.
+    Idle,
+    /// Dead ephemerons have been cleared, and the heap is waiting to be marked.
+    Traced(CollectionKind),
+    /// The heap has been marked, and is waiting to be swept. In lazy-sweep
+    /// mode, it's swept a little at a time by the allocations that follow.
+    Marked(CollectionKind),
+    /// The heap has been swept, and is waiting to be compacted.
+    Swept,
+}
+
+/// How much the interpreter wants a collection at a yield point. Whether one
+/// actually happens is up to the `GcPolicy`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum GcYield {
+    /// Don't collect here.
+    Never,
+    /// Collect if the policy thinks the heap has grown enough to be worth it.
+    Allowed,
+    /// This is a good place to collect, e.g. a function returning after
+    /// allocating a lot of temporaries, so collect if anything has been
+    /// allocated since the last collection.
+    Preferred,
+    /// Like `Allowed`, but only ever do a minor collection. That only spares
+    /// the ephemeron tables, not any of the work of marking and sweeping.
+    MinorOnly,
+    /// Always do a major collection.
+    Forced,
+}
+
+/// Decides whether a yield point actually collects, based on how much has
+/// been allocated since the last collection and how far the heap has grown.
+/// Allocation is counted rather than timed, so this is a threshold on the
+/// number of allocations between collections, not on an allocation rate: a
+/// slow trickle of allocations triggers a collection as surely as a burst.
+/// min_allocs: The number of allocations since the last collection below
+///             which an `Allowed` or `MinorOnly` yield won't collect.
+/// min_heap: The heap size, in cells, below which an `Allowed` or
+///           `MinorOnly` yield won't collect.
+/// growth_factor: How many times larger than it was after the last collection
+///                the heap has to grow before an `Allowed` or `MinorOnly`
+///                yield collects.
+#[derive(Clone, Debug)]
+pub struct GcPolicy {
+    pub min_allocs: usize,
+    pub min_heap: usize,
+    pub growth_factor: f64,
+    allocs_since_gc: usize,
+    live_after_gc: usize,
+}
+
+impl Default for GcPolicy {
+    fn default() -> GcPolicy {
+        GcPolicy {
+            min_allocs: 64,
+            min_heap: 256,
+            growth_factor: 2.,
+            allocs_since_gc: 0,
+            live_after_gc: 0,
+        }
+    }
+}
+
+impl GcPolicy {
+    pub fn new() -> GcPolicy {
+        GcPolicy::default()
+    }
+
+    #[inline]
+    pub fn allocs_since_gc(&self) -> usize {
+        self.allocs_since_gc
+    }
+
+    #[inline]
+    pub fn record_alloc(&mut self) {
+        self.allocs_since_gc += 1;
+    }
+
+    /// Called whenever a collection cycle finishes, with the number of cells
+    /// left in the heap.
+    pub fn record_collection(&mut self, live: usize) {
+        self.allocs_since_gc = 0;
+        self.live_after_gc = live;
+    }
+
+    /// Decide what kind of collection, if any, a yield point should run.
+    pub fn should_collect(&self, gc_yield: GcYield, heap_len: usize) -> Option<CollectionKind> {
+        match gc_yield {
+            GcYield::Never => None,
+            GcYield::Forced => Some(CollectionKind::Major),
+            GcYield::Preferred => {
+                if self.allocs_since_gc > 0 {
+                    Some(CollectionKind::Major)
+                } else {
+                    None
+                }
+            }
+            GcYield::Allowed => {
+                if self.heap_grown(heap_len) {
+                    Some(CollectionKind::Major)
+                } else {
+                    None
+                }
+            }
+            GcYield::MinorOnly => {
+                if self.heap_grown(heap_len) {
+                    Some(CollectionKind::Minor)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn heap_grown(&self, heap_len: usize) -> bool {
+        let threshold = self.live_after_gc as f64 * self.growth_factor;
+        self.allocs_since_gc >= self.min_allocs && heap_len >= self.min_heap &&
+        heap_len as f64 >= threshold
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_never_and_forced() {
+        let mut policy = GcPolicy::new();
+        assert_eq!(policy.should_collect(GcYield::Never, 1_000_000), None);
+        assert_eq!(policy.should_collect(GcYield::Forced, 0), Some(CollectionKind::Major));
+        policy.record_alloc();
+        assert_eq!(policy.should_collect(GcYield::Never, 1_000_000), None);
+    }
+
+    #[test]
+    fn test_preferred() {
+        let mut policy = GcPolicy::new();
+        assert_eq!(policy.should_collect(GcYield::Preferred, 0), None);
+        policy.record_alloc();
+        assert_eq!(policy.should_collect(GcYield::Preferred, 1),
+                   Some(CollectionKind::Major));
+        policy.record_collection(1);
+        assert_eq!(policy.should_collect(GcYield::Preferred, 1), None);
+    }
+
+    #[test]
+    fn test_allowed() {
+        let mut policy = GcPolicy {
+            min_allocs: 2,
+            min_heap: 4,
+            growth_factor: 2.,
+            ..GcPolicy::new()
+        };
+        policy.record_collection(4);
+        policy.record_alloc();
+        // Not enough allocation yet
+        assert_eq!(policy.should_collect(GcYield::Allowed, 8), None);
+        policy.record_alloc();
+        // The heap hasn't doubled yet
+        assert_eq!(policy.should_collect(GcYield::Allowed, 7), None);
+        assert_eq!(policy.should_collect(GcYield::Allowed, 8), Some(CollectionKind::Major));
+        assert_eq!(policy.should_collect(GcYield::MinorOnly, 8),
+                   Some(CollectionKind::Minor));
+        // Tiny heaps are never worth collecting
+        policy.record_collection(0);
+        policy.record_alloc();
+        policy.record_alloc();
+        assert_eq!(policy.should_collect(GcYield::Allowed, 3), None);
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::hash_map::HashMap;
+use std::sync::{Arc, Mutex};
+
+use jsrs_common::types::js_var::{JsType, JsVar};
+use jsrs_common::types::binding::UniqueBinding;
+
+/// Identifies a value held through a `RootHandle` or a `HandleScope`. Ids
+/// aren't reused, so an id that outlives its handle just stops resolving.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub struct HandleId(usize);
+
+/// The values the embedder holds through handles. A `ScopeManager` shares
+/// its table with every handle it gives out, so that a handle can let go of
+/// its value when it's dropped without going through the manager. A handle
+/// may be dropped while the heap is locked, so the heap references of
+/// released values are queued for the manager to release before its next
+/// collection.
+#[derive(Clone, Debug, Default)]
+pub struct HandleTable(Arc<Mutex<Handles>>);
+
+#[derive(Debug, Default)]
+struct Handles {
+    values: HashMap<HandleId, JsVar>,
+    released: Vec<UniqueBinding>,
+    next_id: usize,
+}
+
+impl HandleTable {
+    pub fn new() -> HandleTable {
+        HandleTable::default()
+    }
+
+    /// The number of values being held.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().values.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Hold a value. If it's a pointer, the caller must already have given
+    /// it a heap reference of its own.
+    pub fn hold(&self, var: JsVar) -> HandleId {
+        let mut handles = self.0.lock().unwrap();
+        let id = HandleId(handles.next_id);
+        handles.next_id += 1;
+        handles.values.insert(id, var);
+        id
+    }
+
+    pub fn get(&self, id: HandleId) -> Option<JsVar> {
+        self.0.lock().unwrap().values.get(&id).cloned()
+    }
+
+    /// Stop holding a value, queueing its heap reference to be released.
+    pub fn release(&self, id: HandleId) {
+        let mut handles = self.0.lock().unwrap();
+        if let Some(var) = handles.values.remove(&id) {
+            if let JsType::JsPtr(_) = var.t {
+                handles.released.push(var.unique);
+            }
+        }
+    }
+
+    /// Take the heap references of the values released since the last call.
+    pub fn take_released(&self) -> Vec<UniqueBinding> {
+        self.0.lock().unwrap().released.drain(..).collect()
+    }
+
+    /// The heap references of every value being held.
+    pub fn roots(&self) -> Vec<UniqueBinding> {
+        self.0
+            .lock()
+            .unwrap()
+            .values
+            .values()
+            .filter(|var| matches!(var.t, JsType::JsPtr(_)))
+            .map(|var| var.unique.clone())
+            .collect()
+    }
+}
+
+/// Keeps a value alive until the handle is dropped, for host code that holds
+/// on to a JS value across calls, e.g. a callback stored in a Rust struct.
+#[derive(Debug)]
+pub struct RootHandle {
+    id: HandleId,
+    table: HandleTable,
+}
+
+impl RootHandle {
+    pub fn new(id: HandleId, table: &HandleTable) -> RootHandle {
+        RootHandle {
+            id: id,
+            table: table.clone(),
+        }
+    }
+
+    #[inline]
+    pub fn id(&self) -> HandleId {
+        self.id
+    }
+}
+
+impl Drop for RootHandle {
+    fn drop(&mut self) {
+        self.table.release(self.id);
+    }
+}
+
+/// Keeps the values of any number of handles alive until the scope is
+/// dropped, for temporaries host code only needs for the duration of a call.
+/// Its handles are plain `HandleId`s, all released along with the scope.
+#[derive(Debug)]
+pub struct HandleScope {
+    ids: Vec<HandleId>,
+    table: HandleTable,
+}
+
+impl HandleScope {
+    pub fn new(table: &HandleTable) -> HandleScope {
+        HandleScope {
+            ids: Vec::new(),
+            table: table.clone(),
+        }
+    }
+
+    /// The number of handles in the scope.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    pub fn push(&mut self, id: HandleId) {
+        self.ids.push(id);
+    }
+}
+
+impl Drop for HandleScope {
+    fn drop(&mut self) {
+        for id in self.ids.drain(..) {
+            self.table.release(id);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use jsrs_common::test_utils;
+
+    #[test]
+    fn test_release_on_drop() {
+        let table = HandleTable::new();
+        let (s, _) = test_utils::make_str("s");
+        let s_unique = s.unique.clone();
+        let handle = RootHandle::new(table.hold(s), &table);
+        let id = handle.id();
+        let n = table.hold(test_utils::make_num(1.));
+        assert_eq!(table.roots(), vec![s_unique.clone()]);
+        assert!(table.take_released().is_empty());
+
+        drop(handle);
+        assert!(table.get(id).is_none());
+        assert!(table.get(n).is_some());
+        assert!(table.roots().is_empty());
+        assert_eq!(table.take_released(), vec![s_unique]);
+        assert!(table.take_released().is_empty());
+    }
+
+    #[test]
+    fn test_handle_scope() {
+        let table = HandleTable::new();
+        let mut scope = HandleScope::new(&table);
+        let (a, _) = test_utils::make_str("a");
+        let (b, _) = test_utils::make_str("b");
+        let a = table.hold(a);
+        scope.push(a);
+        let b = table.hold(b);
+        scope.push(b);
+        scope.push(table.hold(test_utils::make_num(1.)));
+        assert_eq!(scope.len(), 3);
+        assert_eq!(table.roots().len(), 2);
+
+        drop(scope);
+        assert!(table.is_empty());
+        assert!(table.get(a).is_none());
+        // Only pointers have heap references to release
+        assert_eq!(table.take_released().len(), 2);
+    }
+}
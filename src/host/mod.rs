@@ -0,0 +1,69 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::fmt;
+
+use alloc::ObjectId;
+
+/// Rust-side state kept in the heap along with a JS object, e.g. a file
+/// handle, a buffer or some native state, so that scripts can hold on to it.
+/// Allocated with `ScopeManager::alloc_host`, and freed along with its
+/// object. Host objects are traced by background marks, on another thread,
+/// so they have to be `Send`.
+pub trait HostObject: Send {
+    /// Add every object this one refers to (see `ScopeManager::object_id`)
+    /// to `objects`. Called whenever the object is traced, so the objects
+    /// live for as long as it reports them, whatever becomes of the
+    /// variables they were taken from.
+    fn trace(&self, objects: &mut Vec<ObjectId>);
+
+    /// Called when the collector sweeps the object, just before it's
+    /// dropped, e.g. to close a file. The heap is borrowed while this runs,
+    /// so it mustn't call back into the `ScopeManager`.
+    fn on_sweep(&mut self) {}
+
+    /// The object itself, for downcasting back to its concrete type.
+    fn as_any(&mut self) -> &mut Any;
+}
+
+/// The host object attached to a heap object.
+pub struct HostCell(pub RefCell<Box<HostObject>>);
+
+impl HostCell {
+    pub fn new(host: Box<HostObject>) -> HostCell {
+        HostCell(RefCell::new(host))
+    }
+
+    /// Run the host object's drop hook, and drop it.
+    pub fn sweep(self) {
+        self.0.into_inner().on_sweep();
+    }
+}
+
+impl fmt::Debug for HostCell {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HostCell")
+    }
+}
+
+/// A host object for tests, which refers to the given objects and counts
+/// how many times it has been swept.
+#[cfg(test)]
+pub struct SweepCounter {
+    pub objects: Vec<ObjectId>,
+    pub swept: ::std::sync::Arc<::std::sync::atomic::AtomicUsize>,
+}
+
+#[cfg(test)]
+impl HostObject for SweepCounter {
+    fn trace(&self, objects: &mut Vec<ObjectId>) {
+        objects.extend(self.objects.iter().cloned());
+    }
+
+    fn on_sweep(&mut self) {
+        self.swept.fetch_add(1, ::std::sync::atomic::Ordering::SeqCst);
+    }
+
+    fn as_any(&mut self) -> &mut Any {
+        self
+    }
+}
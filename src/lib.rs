@@ -11,36 +11,363 @@ extern crate jsrs_common;
 #[macro_use]
 extern crate matches;
 
+pub mod alloc;
+mod concurrent;
+mod error;
+mod events;
+mod finalize;
+mod gc;
+mod handles;
+mod host;
+mod roots;
 mod scope;
+mod snapshot;
+mod stats;
+mod verify;
+mod weak;
 
 use std::cell::RefCell;
 use std::collections::hash_map::HashMap;
+use std::collections::hash_set::HashSet;
+use std::io::{self, Write};
 use std::rc::Rc;
+use std::result;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
-use jsrs_common::alloc_box::AllocBox;
+use jsrs_common::alloc_box::AllocBox as Nursery;
 use jsrs_common::ast::Exp;
 use jsrs_common::backend::Backend;
-use jsrs_common::types::js_var::{JsPtrEnum, JsVar};
+use jsrs_common::types::js_var::{JsKey, JsPtrEnum, JsPtrTag, JsType, JsVar};
 use jsrs_common::types::binding::{Binding, UniqueBinding};
 
 use jsrs_common::gc_error::{GcError, Result};
+use alloc::{AllocBox, MarkSet, NurseryHolder};
+use concurrent::{BackgroundMark, WriteBarrier};
+use events::EventLog;
+use finalize::FinalizationRegistry;
+use handles::HandleTable;
 use scope::{LookupError, Scope, ScopeTag, StoreError};
+pub use error::{HeapError, HeapResult};
+use snapshot::{EdgeType, HeapSnapshot};
+use stats::StatsTracker;
+pub use alloc::{CellRef, ObjectId, OwnNursery};
+pub use events::GcEvent;
+pub use finalize::FinalizationJob;
+pub use gc::{CollectionKind, CollectionResult, GcPhase, GcPolicy, GcYield};
+pub use handles::{HandleId, HandleScope, RootHandle};
+pub use host::HostObject;
+pub use roots::{RootProvider, RootProviderId};
+pub use stats::GcStats;
+pub use verify::{VerifyFailure, Violation};
+pub use weak::{EphemeronId, EphemeronTable, WeakRef};
 
-pub struct ScopeManager {
+/// The number of references and cells each allocation sweeps in lazy-sweep
+/// mode.
+pub const LAZY_SWEEP_BUDGET: usize = 64;
+
+/// The scopes of a running program, and the collector for its heap.
+/// nursery: Where the interpreter builds new objects, which is lent to the
+///          heap for each operation that promotes them into it. By default
+///          it's the `Rc` that `Backend::get_alloc_box` hands out, which
+///          can't be sent to another thread; a `SendScopeManager` leaves it
+///          in the heap instead.
+pub struct ScopeManager<N = Rc<RefCell<Nursery>>> {
     scopes: Vec<Scope>,
     closures: HashMap<UniqueBinding, Scope>,
-    pub alloc_box: Rc<RefCell<AllocBox>>,
+    pub alloc_box: Arc<Mutex<AllocBox>>,
+    nursery: N,
+    heap_limit: Option<usize>,
+    ephemerons: HashMap<EphemeronId, EphemeronTable>,
+    next_ephemeron_id: usize,
+    finalizers: FinalizationRegistry,
+    handles: HandleTable,
+    root_providers: Vec<(RootProviderId, Arc<RootProvider>)>,
+    next_root_provider_id: usize,
+    compacting: bool,
+    lazy_sweep: bool,
+    phase: GcPhase,
+    policy: GcPolicy,
+    stats: StatsTracker,
+    event_log: Option<EventLog>,
+    cycle_start: Option<(Instant, CollectionKind)>,
+    cycle_freed: usize,
+    verify_heap: bool,
+    verify_failures: Vec<VerifyFailure>,
+    gc_stress: bool,
+    barrier: WriteBarrier,
+    background_mark: Option<BackgroundMark>,
 }
 
+/// A `ScopeManager` that can be sent to another thread, e.g. to hand a whole
+/// runtime over to a worker. It doesn't implement `Backend`, since there's no
+/// `Rc` nursery to hand out: objects are built in the heap's own nursery,
+/// `AllocBox::nursery_mut`, and variables are allocated and stored with
+/// `try_alloc` and `try_store`.
+pub type SendScopeManager = ScopeManager<OwnNursery>;
+
 impl ScopeManager {
-    fn new(alloc_box: Rc<RefCell<AllocBox>>) -> ScopeManager {
+    fn new(alloc_box: Arc<Mutex<AllocBox>>) -> ScopeManager {
+        let barrier = WriteBarrier::new();
         ScopeManager {
-            scopes: vec![Scope::new(ScopeTag::Call, &alloc_box)],
+            scopes: vec![Scope::with_barrier(ScopeTag::Call, &alloc_box, &barrier)],
             closures: HashMap::new(),
             alloc_box: alloc_box,
+            nursery: Rc::new(RefCell::new(Nursery::new())),
+            heap_limit: None,
+            ephemerons: HashMap::new(),
+            next_ephemeron_id: 0,
+            finalizers: FinalizationRegistry::new(),
+            handles: HandleTable::new(),
+            root_providers: Vec::new(),
+            next_root_provider_id: 0,
+            compacting: false,
+            lazy_sweep: false,
+            phase: GcPhase::Idle,
+            policy: GcPolicy::new(),
+            stats: StatsTracker::new(),
+            event_log: None,
+            cycle_start: None,
+            cycle_freed: 0,
+            verify_heap: false,
+            verify_failures: Vec::new(),
+            gc_stress: false,
+            barrier: barrier,
+            background_mark: None,
+        }
+    }
+
+    /// Make the manager `Send`, moving the interpreter's nursery into the
+    /// heap. This fails, handing the manager back as it was, while anything
+    /// else still holds the nursery, e.g. the interpreter through
+    /// `get_alloc_box`, or a `CellRef`.
+    pub fn into_send(self) -> result::Result<SendScopeManager, ScopeManager> {
+        if Rc::strong_count(&self.nursery) > 1 {
+            return Err(self);
+        }
+        self.nursery.swap_nursery(self.alloc_box.lock().unwrap().nursery_mut());
+        Ok(self.replace_nursery(OwnNursery))
+    }
+}
+
+impl SendScopeManager {
+    /// Move the heap's nursery back out into an `Rc`, for a manager that
+    /// implements `Backend` again.
+    pub fn into_local(self) -> ScopeManager {
+        let nursery = Rc::new(RefCell::new(Nursery::new()));
+        nursery.swap_nursery(self.alloc_box.lock().unwrap().nursery_mut());
+        self.replace_nursery(nursery)
+    }
+}
+
+impl<N: NurseryHolder + Clone> ScopeManager<N> {
+    /// Move everything but the nursery into a manager that keeps its nursery
+    /// in `nursery`.
+    fn replace_nursery<M>(self, nursery: M) -> ScopeManager<M> {
+        ScopeManager {
+            scopes: self.scopes,
+            closures: self.closures,
+            alloc_box: self.alloc_box,
+            nursery: nursery,
+            heap_limit: self.heap_limit,
+            ephemerons: self.ephemerons,
+            next_ephemeron_id: self.next_ephemeron_id,
+            finalizers: self.finalizers,
+            handles: self.handles,
+            root_providers: self.root_providers,
+            next_root_provider_id: self.next_root_provider_id,
+            compacting: self.compacting,
+            lazy_sweep: self.lazy_sweep,
+            phase: self.phase,
+            policy: self.policy,
+            stats: self.stats,
+            event_log: self.event_log,
+            cycle_start: self.cycle_start,
+            cycle_freed: self.cycle_freed,
+            verify_heap: self.verify_heap,
+            verify_failures: self.verify_failures,
+            gc_stress: self.gc_stress,
+            barrier: self.barrier,
+            background_mark: self.background_mark,
+        }
+    }
+
+    /// Run `f` on the heap, with the interpreter's nursery lent to it.
+    fn with_heap<R, F>(&self, f: F) -> R
+        where F: FnOnce(&mut AllocBox) -> R
+    {
+        self.alloc_box.lock().unwrap().with_nursery(&self.nursery, f)
+    }
+
+    /// Start writing an event to `sink`, as a line of JSON, whenever something
+    /// happens that's relevant to the collector, or stop if `sink` is `None`.
+    /// If writing to the sink ever fails, the log is dropped.
+    pub fn set_event_sink(&mut self, sink: Option<Box<Write + Send>>) {
+        if let Some(ref mut log) = self.event_log {
+            log.flush().ok();
+        }
+        self.event_log = sink.map(EventLog::new);
+    }
+
+    /// Log an event if there's a sink to log it to. The event is only built
+    /// when it's needed, so this costs next to nothing when logging is off.
+    fn log_event<F: FnOnce() -> GcEvent>(&mut self, event: F) {
+        let failed = match self.event_log {
+            Some(ref mut log) => log.record(&event()).is_err(),
+            None => false,
+        };
+        if failed {
+            self.event_log = None;
+        }
+    }
+
+    /// In GC stress mode, a full collection runs after every `alloc` and
+    /// `store`, and on every `pop_scope` whatever its `gc_yield`. Any value
+    /// that isn't properly rooted is collected at the first opportunity, so
+    /// missing roots show up deterministically instead of by bad luck.
+    pub fn set_gc_stress(&mut self, stress: bool) {
+        self.gc_stress = stress;
+    }
+
+    fn stress_collect(&mut self) {
+        if self.gc_stress {
+            self.collect(CollectionKind::Full);
+        }
+    }
+
+    /// In heap verification mode, `verify` runs after every scope push and
+    /// pop, allocation, store and collection, and whatever it finds is kept
+    /// for `take_verify_failures`. This is slow, and meant for debugging the
+    /// collector and its embedders.
+    pub fn set_verify_heap(&mut self, verify: bool) {
+        self.verify_heap = verify;
+    }
+
+    /// Take the failures heap verification mode has found since the last
+    /// call, oldest first.
+    pub fn take_verify_failures(&mut self) -> Vec<VerifyFailure> {
+        self.verify_failures.drain(..).collect()
+    }
+
+    /// Check the invariants the scopes and the heap rely on, and return every
+    /// violation found.
+    pub fn verify(&self) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        for (depth, scope) in self.scopes.iter().enumerate() {
+            scope.verify(&format!("scope {} ({:?})", depth, scope.tag),
+                         &mut violations);
+        }
+        for (unique, scope) in &self.closures {
+            scope.verify(&format!("closure environment of {:?}", unique),
+                         &mut violations);
+        }
+        // Ephemeron values are held until their tables are next cleared,
+        // whether or not their keys are still alive.
+        let mut reachable = self.reachable();
+        let held = self.ephemerons
+                       .values()
+                       .flat_map(|table| table.value_edges())
+                       .map(|(_, unique)| unique)
+                       .collect();
+        let heap = self.alloc_box.lock().unwrap();
+        heap.trace(&mut reachable, held, Vec::new());
+        for object in reachable.refs {
+            if let Some(alloc) = heap.find_id(&object) {
+                if let JsPtrEnum::JsObj(ref obj) = *alloc.borrow() {
+                    for (key, var) in &obj.dict {
+                        if let JsType::JsPtr(_) = var.t {
+                            if !heap.is_allocated(&var.unique) {
+                                violations.push(Violation::DanglingProperty {
+                                    object: object.clone(),
+                                    key: key.clone(),
+                                    unique: var.unique.clone(),
+                                });
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        for unique in self.finalizers.roots() {
+            if !heap.is_allocated(&unique) {
+                violations.push(Violation::DanglingHeldValue {
+                    location: "finalizers".to_owned(),
+                    unique: unique,
+                });
+            }
+        }
+        for (id, table) in &self.ephemerons {
+            for (_, unique) in table.value_edges() {
+                if !heap.is_allocated(&unique) {
+                    violations.push(Violation::DanglingHeldValue {
+                        location: format!("ephemeron table {}", id.0),
+                        unique: unique,
+                    });
+                }
+            }
+        }
+        for unique in self.handles.roots() {
+            if !heap.is_allocated(&unique) {
+                violations.push(Violation::DanglingHandle { unique: unique });
+            }
+        }
+        violations
+    }
+
+    /// Run `verify` if heap verification mode is on, and keep anything it
+    /// finds wrong for `take_verify_failures`.
+    fn verify_after(&mut self, op: &str) {
+        if !self.verify_heap {
+            return;
+        }
+        let violations = self.verify();
+        if !violations.is_empty() {
+            self.verify_failures.push(VerifyFailure {
+                op: op.to_owned(),
+                violations: violations,
+            });
+        }
+    }
+
+    /// Take a snapshot of the collector's statistics so far.
+    pub fn gc_stats(&self) -> GcStats {
+        self.stats.snapshot(&*self.alloc_box.lock().unwrap())
+    }
+
+    /// Set the maximum size of the heap in bytes, as estimated by `ptr_size`
+    /// over the objects in it, or `None` for no limit.
+    pub fn set_heap_limit(&mut self, limit: Option<usize>) {
+        self.heap_limit = limit;
+    }
+
+    pub fn heap_limit(&self) -> Option<usize> {
+        self.heap_limit
+    }
+
+    /// In lazy-sweep mode, yielding to the collector only marks the heap. The
+    /// sweep is spread over the allocations that follow, each sweeping up to
+    /// `LAZY_SWEEP_BUDGET` references and cells.
+    pub fn set_lazy_sweep(&mut self, lazy: bool) {
+        self.lazy_sweep = lazy;
+        if !lazy {
+            self.finish_sweep();
         }
     }
 
+    /// Replace the policy that decides whether yield points collect.
+    pub fn set_gc_policy(&mut self, policy: GcPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn gc_policy(&self) -> &GcPolicy {
+        &self.policy
+    }
+
+    /// Turn the compaction phase that follows each major collection on or off.
+    pub fn set_compacting(&mut self, compacting: bool) {
+        self.compacting = compacting;
+    }
+
     #[allow(dead_code)]
     #[inline]
     fn curr_scope(&self) -> &Scope {
@@ -65,6 +392,10 @@ impl ScopeManager {
     pub fn push_closure_scope(&mut self, closure: &UniqueBinding) -> Result<()> {
         let closure_scope = self.closures.remove(closure).ok_or(GcError::Scope)?;
         self.scopes.push(closure_scope);
+        let depth = self.scopes.len();
+        self.stats.record_scope_depth(depth);
+        self.log_event(|| GcEvent::ScopePush { depth: depth });
+        self.verify_after("push_closure_scope");
         Ok(())
     }
 
@@ -73,392 +404,2947 @@ impl ScopeManager {
             Exp::Call(..) => ScopeTag::Call,
             _ => ScopeTag::Block,
         };
-        self.scopes.push(Scope::new(tag, &self.alloc_box));
+        self.scopes.push(Scope::with_barrier(tag, &self.alloc_box, &self.barrier));
+        let depth = self.scopes.len();
+        self.stats.record_scope_depth(depth);
+        self.log_event(|| GcEvent::ScopePush { depth: depth });
+        self.verify_after("push_scope");
     }
 
     pub fn pop_scope(&mut self,
                      returning_closure: Option<UniqueBinding>,
-                     gc_yield: bool)
+                     gc_yield: GcYield)
                      -> Result<()> {
+        if self.scopes.len() == 1 {
+            // Don't leave a collection hanging when the program ends.
+            self.finish_cycle();
+        }
         if let Some(mut scope) = self.scopes.pop() {
+            let depth = self.scopes.len();
+            self.log_event(|| {
+                GcEvent::ScopePop {
+                    depth: depth,
+                    gc_yield: gc_yield,
+                }
+            });
             // Clean up the dying scope's stack and take ownership of its heap-allocated data for
             // later collection
             if self.scopes.is_empty() {
                 // The global scope was popped and the program is ending.
-                scope.trigger_gc();
+                self.collect(CollectionKind::Major);
                 return Err(GcError::Scope);
             }
             if let Some(unique) = returning_closure {
-                let mut closure_scope = Scope::new(ScopeTag::Closure(unique.clone()),
-                                                   &self.alloc_box);
-                scope.transfer_stack(&mut closure_scope, true)?;
+                let mut closure_scope = Scope::with_barrier(ScopeTag::Closure(unique.clone()),
+                                                            &self.alloc_box,
+                                                            &self.barrier);
+                let res = scope.transfer_stack(&mut closure_scope, true);
+                if let Err(e) = res {
+                    // Nothing was transferred, so put the scope back as it was
+                    self.scopes.push(scope);
+                    return Err(e);
+                }
                 self.closures.insert(unique, closure_scope);
-            } else {
-                if !matches!(scope.tag, ScopeTag::Closure(_)) {
-                    scope.transfer_stack(self.curr_scope_mut(), false)?
+            } else if !matches!(scope.tag, ScopeTag::Closure(_)) {
+                let res = scope.transfer_stack(self.curr_scope_mut(), false);
+                if let Err(e) = res {
+                    self.scopes.push(scope);
+                    return Err(e);
                 }
             }
             // Potentially trigger the garbage collector
-            if gc_yield {
-                self.curr_scope_mut().trigger_gc();
+            let heap_len = self.alloc_box.lock().unwrap().len();
+            if self.gc_stress {
+                self.collect(CollectionKind::Full);
+            } else if let Some(kind) = self.policy.should_collect(gc_yield, heap_len) {
+                if self.lazy_sweep {
+                    self.mark_heap(kind);
+                } else {
+                    self.collect(kind);
+                }
             }
             if let ScopeTag::Closure(unique) = scope.tag.clone() {
                 self.closures.insert(unique.clone(), scope);
             }
+            self.verify_after("pop_scope");
             Ok(())
         } else {
             Err(GcError::Scope)
         }
     }
 
-    pub fn rename_closure(&mut self, old: &UniqueBinding, new: &UniqueBinding) -> bool {
-        if self.closures.contains_key(old) {
-            let mut scope = self.closures.remove(old).unwrap();
-            scope.tag = ScopeTag::Closure(new.clone());
-            self.closures.insert(new.clone(), scope);
-            true
-        } else {
-            false
+    /// Run a collection right now, finishing any cycle that was already in
+    /// progress first. This may be called whenever the scope stack is
+    /// consistent, i.e. between any two `Backend` operations. Every kind of
+    /// collection visits the whole heap, so `freed` counts everything found
+    /// dead; after a `Minor` one it leaves out the values of ephemerons
+    /// whose keys have died, which are kept until the next major collection.
+    pub fn collect(&mut self, kind: CollectionKind) -> CollectionResult {
+        let start = Instant::now();
+        self.finish_cycle();
+        self.gc_step(kind);
+        self.finish_cycle();
+        self.stats.record_pause(start);
+        let live = self.alloc_box.lock().unwrap().len();
+        CollectionResult {
+            freed: self.cycle_freed,
+            live: live,
         }
     }
-}
-
-impl Backend for ScopeManager {
-    fn alloc(&mut self, var: JsVar, ptr: Option<JsPtrEnum>) -> Result<Binding> {
-        let binding = var.binding.clone();
-        let is_allocated = self.alloc_box.borrow().is_allocated(&var.unique);
 
-        // If the ptr is already allocated in the heap, just push it onto the stack
-        if is_allocated && ptr.is_some() {
-            self.curr_scope_mut().bind_var(var);
-        } else {
-            self.curr_scope_mut().push_var(var, ptr)?;
+    /// Do as much collection work as fits before `deadline`, starting a new
+    /// major cycle if none is in progress, and return whether the cycle was
+    /// finished. Work is done a phase at a time, so a phase that has started
+    /// when the deadline passes still runs to completion.
+    pub fn collect_until(&mut self, deadline: Instant) -> bool {
+        let start = Instant::now();
+        let mut finished = false;
+        while !finished && Instant::now() < deadline {
+            finished = self.gc_step(CollectionKind::Major);
         }
-        Ok(binding)
+        self.stats.record_pause(start);
+        finished
     }
 
-    /// Try to load the variable behind a binding
-    fn load(&mut self, bnd: &Binding) -> Result<(JsVar, Option<JsPtrEnum>)> {
-        let lookup = || {
-            for scope in self.scopes.iter().rev() {
-                match scope.get_var_copy(bnd) {
-                    Ok(v) => {
-                        return Ok(v);
-                    }
-                    Err(LookupError::FnBoundary) => {
-                        return Err(GcError::Load(bnd.clone()));
+    #[inline]
+    pub fn gc_phase(&self) -> GcPhase {
+        self.phase
+    }
+
+    /// Do the next phase of the current collection cycle, starting a new cycle
+    /// of the given kind if none is in progress. Returns whether the cycle has
+    /// finished.
+    fn gc_step(&mut self, kind: CollectionKind) -> bool {
+        let phase = self.phase;
+        self.phase = match phase {
+            GcPhase::Idle => {
+                self.cancel_background_mark();
+                let live = self.alloc_box.lock().unwrap().len();
+                self.cycle_start = Some((Instant::now(), kind));
+                self.cycle_freed = 0;
+                self.release_handles();
+                self.log_event(|| {
+                    GcEvent::CollectionStart {
+                        kind: kind,
+                        live: live,
                     }
-                    Err(LookupError::CheckParent) => {}
-                    Err(LookupError::Unreachable) => unreachable!(),
+                });
+                if kind.clears_ephemerons() {
+                    self.clear_dead_ephemerons();
                 }
+                GcPhase::Traced(kind)
             }
-            Err(GcError::Load(bnd.clone()))
-        };
-        match lookup() {
-            Ok(v) => Ok(v),
-            Err(GcError::Load(bnd)) => {
-                self.global_scope()
-                    .get_var_copy(&bnd)
-                    .map_err(|_| GcError::Load(bnd.clone()))
+            GcPhase::Traced(kind) => {
+                let extra = self.provider_roots();
+                self.alloc_box.lock().unwrap().mark_ptrs_from(extra);
+                GcPhase::Marked(kind)
             }
-            _ => unreachable!(),
-        }
-    }
-
-    fn store(&mut self, var: JsVar, ptr: Option<JsPtrEnum>) -> Result<()> {
-        let (mut var, mut ptr) = (var, ptr);
-        let lookup = {
-            let mut res = Err(GcError::Store(var.clone(), ptr.clone()));
-            for ref mut scope in self.scopes.iter_mut().rev() {
-                match scope.update_var(var, ptr) {
-                    Ok(()) => {
-                        res = Ok(());
-                        break;
-                    }
-                    Err(StoreError::CheckParent(v, p)) => {
-                        var = v;
-                        ptr = p;
-                    }
-                    Err(StoreError::FnBoundary(v, p)) => {
-                        res = Err(GcError::Store(v, p));
-                        break;
-                    }
-                    Err(StoreError::PtrTypeMismatch) |
-                    Err(StoreError::BadStore) => {
-                        res = Err(GcError::PtrAlloc);
-                        break;
-                    }
+            GcPhase::Marked(kind) => {
+                self.sweep_heap();
+                if kind == CollectionKind::Full ||
+                   (kind == CollectionKind::Major && self.compacting) {
+                    GcPhase::Swept
+                } else {
+                    GcPhase::Idle
                 }
             }
-            res
+            GcPhase::Swept => {
+                self.compact_heap();
+                GcPhase::Idle
+            }
         };
-        match lookup {
-            Ok(()) => Ok(()),
-            Err(GcError::Store(var, ptr)) => {
-                self.global_scope_mut()
-                    .update_var(var.clone(), ptr.clone())
-                    .map_err(|_| GcError::Store(var, ptr))
+        if self.phase == GcPhase::Idle {
+            let live = self.alloc_box.lock().unwrap().len();
+            self.policy.record_collection(live);
+            self.stats.record_collection();
+            if let Some((start, kind)) = self.cycle_start.take() {
+                let freed = self.cycle_freed;
+                self.log_event(|| {
+                    GcEvent::CollectionEnd {
+                        kind: kind,
+                        freed: freed,
+                        live: live,
+                        duration: start.elapsed(),
+                    }
+                });
             }
-            Err(_) => lookup,
+            self.verify_after("collection");
+            true
+        } else {
+            false
         }
-        // let res = self.curr_scope_mut().update_var(var, ptr);
-        // if let Err(GcError::Store(var, ptr)) = res {
-        // self.global_scope_mut().update_var(var, ptr)
-        // } else {
-        // res
-        // }
     }
 
-    fn get_alloc_box(&self) -> Rc<RefCell<AllocBox>> {
-        self.alloc_box.clone()
+    fn finish_cycle(&mut self) {
+        while self.phase != GcPhase::Idle {
+            self.gc_step(CollectionKind::Major);
+        }
     }
-}
 
-pub fn init_gc() -> ScopeManager {
-    let alloc_box = Rc::new(RefCell::new(AllocBox::new()));
-    ScopeManager::new(alloc_box)
-}
-
-
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Start a new cycle and take it as far as marking the heap.
+    fn mark_heap(&mut self, kind: CollectionKind) {
+        let start = Instant::now();
+        self.finish_cycle();
+        self.gc_step(kind);
+        self.gc_step(kind);
+        self.stats.record_pause(start);
+    }
 
-    use jsrs_common::ast::Exp;
-    use jsrs_common::backend::Backend;
-    use jsrs_common::gc_error::GcError;
-    use jsrs_common::test_utils;
-    use jsrs_common::types::js_var::{JsKey, JsPtrEnum, JsType, JsVar};
-    use jsrs_common::types::binding::Binding;
+    /// Finish the current collection cycle if its heap has been marked but
+    /// not completely swept yet. The heap may be allocated into in between,
+    /// since anything allocated after the mark is left alone by the sweep,
+    /// but ephemeron tables, finalizers and handles are only brought up to
+    /// date once the sweep is over, so their methods call this first.
+    pub fn finish_sweep(&mut self) {
+        if let GcPhase::Marked(_) = self.phase {
+            let start = Instant::now();
+            self.finish_cycle();
+            self.stats.record_pause(start);
+        }
+    }
 
-    #[test]
-    fn test_push_closure_scope() {
-        let alloc_box = test_utils::make_alloc_box();
-        let mut mgr = ScopeManager::new(alloc_box);
-        mgr.push_scope(&Exp::Undefined);
-        let (fn_var, fn_ptr) = test_utils::make_fn(&None, &Vec::new());
-        let unique = fn_var.unique.clone();
-        mgr.alloc(fn_var, Some(fn_ptr)).unwrap();
-        mgr.pop_scope(Some(unique.clone()), false).unwrap();
-        assert_eq!(mgr.closures.len(), 1);
-        mgr.push_closure_scope(&unique).unwrap();
-        assert_eq!(mgr.closures.len(), 0);
-        mgr.pop_scope(None, false).unwrap();
-        assert_eq!(mgr.closures.len(), 1);
+    /// Sweep the next few references and cells of a heap that's been marked
+    /// but not swept yet, and finish the cycle once they've all been swept.
+    /// Each allocation does this in lazy-sweep mode, so that the cost of a
+    /// sweep is spread over the allocations that follow the mark.
+    fn sweep_step(&mut self) {
+        if let GcPhase::Marked(_) = self.phase {
+            let start = Instant::now();
+            if self.with_heap(|heap| heap.sweep_some(LAZY_SWEEP_BUDGET)) {
+                self.finish_cycle();
+            }
+            self.stats.record_pause(start);
+        }
     }
 
-    #[test]
-    fn test_pop_scope() {
-        let alloc_box = test_utils::make_alloc_box();
-        let mut mgr = ScopeManager::new(alloc_box);
-        mgr.push_scope(&Exp::Undefined);
-        assert_eq!(mgr.scopes.len(), 2);
-        mgr.pop_scope(None, false).unwrap();
-        assert_eq!(mgr.scopes.len(), 1);
+    /// Start marking the heap on a background thread, and return whether a
+    /// mark was started. The marking thread traces the heap the interpreter
+    /// is using, locking it a batch at a time, while the write barrier shared
+    /// by every scope logs the cells written in the meantime. Ephemeron
+    /// values are left for `finish_background_mark` to trace. The
+    /// interpreter is free to carry on until then, but starting any other
+    /// collection first cancels the mark.
+    pub fn start_background_mark(&mut self) -> bool {
+        if self.background_mark.is_some() {
+            return false;
+        }
+        self.finish_cycle();
+        self.release_handles();
+        self.barrier.activate();
+        let live = self.alloc_box.lock().unwrap().len();
+        self.log_event(|| {
+            GcEvent::CollectionStart {
+                kind: CollectionKind::Major,
+                live: live,
+            }
+        });
+        let mark = BackgroundMark::start(&self.alloc_box, self.roots(), self.provider_roots());
+        self.background_mark = Some(mark);
+        true
     }
 
-    #[test]
-    fn test_pop_scope_fail() {
-        let alloc_box = test_utils::make_alloc_box();
-        let mut mgr = ScopeManager::new(alloc_box);
-        let res = mgr.pop_scope(None, false);
-        assert!(res.is_err());
-        assert!(matches!(res, Err(GcError::Scope)));
+    /// Stop the background mark, if one is running, throwing away what it's
+    /// marked, and return whether one was. Every other collection does this
+    /// before it starts, since the mark would be out of date once the heap
+    /// has been swept.
+    pub fn cancel_background_mark(&mut self) -> bool {
+        match self.background_mark.take() {
+            Some(mark) => {
+                mark.cancel();
+                self.barrier.take();
+                true
+            }
+            None => false,
+        }
     }
 
-    #[test]
-    fn test_alloc() {
-        let alloc_box = test_utils::make_alloc_box();
-        let mut mgr = ScopeManager::new(alloc_box);
-        mgr.alloc(test_utils::make_num(1.), None).unwrap();
-        mgr.push_scope(&Exp::Undefined);
-        mgr.alloc(test_utils::make_num(2.), None).unwrap();
-        assert!(mgr.alloc_box.borrow().is_empty());
+    #[inline]
+    pub fn background_mark_running(&self) -> bool {
+        self.background_mark.is_some()
     }
 
-    #[test]
-    fn test_load() {
-        let alloc_box = test_utils::make_alloc_box();
-        let mut mgr = ScopeManager::new(alloc_box);
-        let x = test_utils::make_num(1.);
-        let x_bnd = mgr.alloc(x, None).unwrap();
-        let load = mgr.load(&x_bnd);
-        assert!(load.is_ok());
-        let load = load.unwrap();
-        match load.0.t {
-            JsType::JsNum(n) => assert!(f64::abs(n - 1.) < 0.0001),
-            _ => unreachable!(),
+    /// Wait for the background mark to finish, trace the cells written in the
+    /// meantime, and sweep everything that wasn't marked. Returns `None` if
+    /// no background mark was running.
+    pub fn finish_background_mark(&mut self) -> Option<CollectionResult> {
+        let mark = match self.background_mark.take() {
+            Some(mark) => mark,
+            None => return None,
+        };
+        let cycle_start = mark.start;
+        let pause = Instant::now();
+        let mut marked = mark.join();
+        self.finish_cycle();
+        // Cells written since the mark started may point to cells it never
+        // saw, so they're traced again, along with the roots as they are now.
+        let written = self.barrier.take();
+        {
+            let heap = self.alloc_box.lock().unwrap();
+            for unique in &written {
+                marked.refs.remove(unique);
+                if let Some(id) = heap.object_id(unique) {
+                    marked.objects.remove(&id);
+                }
+            }
         }
-        assert!(load.1.is_none());
+        let grey = written.into_iter().chain(self.roots()).collect();
+        let grey_ids = self.provider_roots();
+        self.trace_from(&mut marked, grey, grey_ids);
+        {
+            let mut heap = self.alloc_box.lock().unwrap();
+            for table in self.ephemerons.values_mut() {
+                table.clear_dead(&marked.objects, &mut *heap);
+            }
+        }
+
+        let freed = self.rebuild_heap(&marked);
+        self.forget_swept();
+        let live = self.alloc_box.lock().unwrap().len();
+        self.policy.record_collection(live);
+        self.stats.record_collection();
+        self.stats.record_pause(pause);
+        self.log_event(|| {
+            GcEvent::CollectionEnd {
+                kind: CollectionKind::Major,
+                freed: freed,
+                live: live,
+                duration: cycle_start.elapsed(),
+            }
+        });
+        self.verify_after("collection");
+        Some(CollectionResult {
+            freed: freed,
+            live: live,
+        })
     }
 
-    #[test]
-    fn test_load_fail() {
-        let alloc_box = test_utils::make_alloc_box();
-        let mut mgr = ScopeManager::new(alloc_box);
-        let bnd = Binding::new("".to_owned());
-        let res = mgr.load(&bnd);
-        assert!(res.is_err());
-        assert!(matches!(res, Err(GcError::Load(_))));
-        if let Err(GcError::Load(res_bnd)) = res {
-            assert_eq!(bnd, res_bnd);
+    fn clear_dead_ephemerons(&mut self) {
+        if self.ephemerons.is_empty() {
+            return;
+        }
+        let marked = self.reachable().objects;
+        let mut heap = self.alloc_box.lock().unwrap();
+        for table in self.ephemerons.values_mut() {
+            table.clear_dead(&marked, &mut *heap);
         }
     }
 
-    #[test]
-    fn test_store() {
-        let alloc_box = test_utils::make_alloc_box();
-        let mut mgr = ScopeManager::new(alloc_box);
-        mgr.push_scope(&Exp::Undefined);
+    fn sweep_heap(&mut self) {
+        let freed = self.with_heap(AllocBox::sweep_ptrs);
+        self.cycle_freed += freed;
+        self.forget_swept();
+    }
 
-        let x = test_utils::make_num(1.);
-        let x_bnd = mgr.alloc(x, None).unwrap();
+    fn forget_swept(&mut self) {
+        // Every scope on the stack and every closure environment was a root,
+        // so all of them have to forget about the cells that were just swept.
+        for scope in self.scopes.iter_mut().chain(self.closures.values_mut()) {
+            scope.prune();
+        }
+        self.finalizers.queue_dead(&*self.alloc_box.lock().unwrap());
+    }
 
-        let (mut var, _) = mgr.load(&x_bnd).unwrap();
-        var.t = JsType::JsNum(2.);
+    /// Compact the cells that survived the last sweep. Every reference to a
+    /// cell, whether from a scope stack, a closure environment or an object
+    /// property, goes through the cell's `ObjectId`, so the survivors can be
+    /// slid down over the slots dead cells left behind without rewriting
+    /// any of them.
+    fn compact_heap(&mut self) {
+        let live = self.reachable();
+        let freed = self.rebuild_heap(&live);
+        self.cycle_freed += freed;
+    }
 
-        assert!(mgr.store(var, None).is_ok());
+    /// Drop everything from the heap but what's in `live`, and compact it.
+    /// Returns the number of objects freed.
+    fn rebuild_heap(&mut self, live: &MarkSet) -> usize {
+        let roots: HashSet<_> = self.roots()
+                                    .into_iter()
+                                    .chain(self.ephemerons
+                                               .values()
+                                               .flat_map(|table| table.live_values(&live.objects)))
+                                    .collect();
+        // Cells only reachable through other cells weren't roots before, so
+        // they mustn't become roots now either.
+        self.alloc_box.lock().unwrap().compact(live, &roots)
     }
 
-    #[test]
-    fn test_store_fail() {
-        let alloc_box = test_utils::make_alloc_box();
-        let mut mgr = ScopeManager::new(alloc_box);
-        let x = test_utils::make_num(1.);
-        assert!(mgr.store(x, None).is_err());
+    /// The heap cells referenced directly by the scope stacks, closure
+    /// environments, values held by finalizers and the embedder's handles.
+    fn roots(&self) -> Vec<UniqueBinding> {
+        self.scopes
+            .iter()
+            .chain(self.closures.values())
+            .flat_map(|scope| scope.vars())
+            .filter(|var| matches!(var.t, JsType::JsPtr(_)))
+            .map(|var| var.unique.clone())
+            .chain(self.finalizers.roots())
+            .chain(self.handles.roots())
+            .collect()
     }
 
-    #[test]
-    fn test_store_to_parent_scope() {
-        let alloc_box = test_utils::make_alloc_box();
-        let mut mgr = ScopeManager::new(alloc_box);
+    /// Release the heap references of the handles dropped since the last
+    /// collection started. A reference may already be gone, if a compaction
+    /// ran after its handle was dropped.
+    fn release_handles(&mut self) {
+        let released = self.handles.take_released();
+        let mut heap = self.alloc_box.lock().unwrap();
+        for unique in released {
+            heap.release(&unique).ok();
+        }
+    }
+
+    /// The objects the registered root providers report. Unlike `roots`,
+    /// these aren't roots in the heap itself, and so don't survive a
+    /// compaction as roots.
+    fn provider_roots(&self) -> Vec<ObjectId> {
+        let mut roots = Vec::new();
+        for &(_, ref provider) in &self.root_providers {
+            provider.roots(&mut roots);
+        }
+        roots
+    }
+
+    /// Find every heap cell reachable from the scope stacks, closure
+    /// environments, values held by finalizers and handles, and the root
+    /// providers. Ephemeron values are only traced once their keys have been
+    /// reached, so a value can't keep its own key alive.
+    fn reachable(&self) -> MarkSet {
+        let mut marked = MarkSet::new();
+        self.trace_from(&mut marked, self.roots(), self.provider_roots());
+        marked
+    }
+
+    /// Mark everything reachable from the references in `grey` and the
+    /// objects in `grey_ids`, including the values of ephemerons whose keys
+    /// end up marked.
+    fn trace_from(&self,
+                  marked: &mut MarkSet,
+                  grey: Vec<UniqueBinding>,
+                  grey_ids: Vec<ObjectId>) {
+        let heap = self.alloc_box.lock().unwrap();
+        heap.trace(marked, grey, grey_ids);
+        loop {
+            let grey: Vec<_> = self.ephemerons
+                                   .values()
+                                   .flat_map(|table| table.live_values(&marked.objects))
+                                   .filter(|unique| !marked.refs.contains(unique))
+                                   .collect();
+            if grey.is_empty() {
+                break;
+            }
+            heap.trace(marked, grey, Vec::new());
+        }
+    }
+
+    /// Write a heap snapshot to `out` in the format Chrome DevTools loads
+    /// from `.heapsnapshot` files. Like V8, we run a major collection first,
+    /// so that every cell left in the heap is reachable from one of the roots
+    /// the snapshot describes: each scope on the stack, which includes the
+    /// mangled bindings transferred from scopes that have exited, each
+    /// closure environment, the values held by finalizers, handles and
+    /// ephemeron tables, and those reported by root providers.
+    pub fn write_heap_snapshot(&mut self, out: &mut Write) -> io::Result<()> {
+        self.collect(CollectionKind::Major);
+        let live = self.reachable();
+        let heap = self.alloc_box.lock().unwrap();
+        let mut snapshot = HeapSnapshot::new(&*heap);
+        for (depth, scope) in self.scopes.iter().enumerate() {
+            let node = snapshot.add_root(&format!("(scope {}: {:?})", depth, scope.tag));
+            add_scope_edges(&mut snapshot, node, scope);
+        }
+        let closures = snapshot.add_root("(closures)");
+        for (unique, scope) in &self.closures {
+            let env = snapshot.add_synthetic(&format!("(closure environment {:?})", unique));
+            snapshot.add_element(closures, env);
+            add_scope_edges(&mut snapshot, env, scope);
+            if let Some(function) = snapshot.cell(unique) {
+                snapshot.add_edge(function, EdgeType::Internal, "context", env);
+            }
+        }
+        let finalizers = snapshot.add_root("(finalizers)");
+        for unique in self.finalizers.roots() {
+            if let Some(held) = snapshot.cell(&unique) {
+                snapshot.add_element(finalizers, held);
+            }
+        }
+        let handles = snapshot.add_root("(handles)");
+        for unique in self.handles.roots() {
+            if let Some(held) = snapshot.cell(&unique) {
+                snapshot.add_element(handles, held);
+            }
+        }
+        let providers = snapshot.add_root("(embedder roots)");
+        for id in self.provider_roots() {
+            if let Some(held) = snapshot.object(id) {
+                snapshot.add_element(providers, held);
+            }
+        }
+        let ephemerons = snapshot.add_root("(ephemeron tables)");
+        for (id, table) in &self.ephemerons {
+            let node = snapshot.add_synthetic(&format!("(ephemeron table {})", id.0));
+            snapshot.add_element(ephemerons, node);
+            for unique in table.live_values(&live.objects) {
+                if let Some(value) = snapshot.cell(&unique) {
+                    snapshot.add_element(node, value);
+                }
+            }
+        }
+        snapshot.write(out)
+    }
+
+    /// Make room under the configured heap limit for `bytes` more bytes
+    /// before allocating them. If they don't fit, run an emergency
+    /// collection, and if that can't free enough, report that we're out of
+    /// memory. Nothing has been changed yet at that point, so a failed
+    /// operation leaves nothing behind.
+    fn reserve(&mut self, bytes: usize) -> HeapResult<()> {
+        if let Some(limit) = self.heap_limit {
+            if bytes > 0 && self.alloc_box.lock().unwrap().bytes() + bytes > limit {
+                self.collect(CollectionKind::Full);
+                if self.alloc_box.lock().unwrap().bytes() + bytes > limit {
+                    return Err(HeapError::OutOfMemory);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The number of bytes storing `ptr` into `var` would add to the heap:
+    /// the size of the pointer's data, and of each of the properties it
+    /// still has in the nursery. Stored `in_place`, a pointer of the same
+    /// type as the variable's current object is written over it, so only
+    /// what it adds to that object's size counts.
+    fn bytes_needed(&self, var: &JsVar, ptr: &Option<JsPtrEnum>, in_place: bool) -> usize {
+        let ptr = match *ptr {
+            Some(ref ptr) => ptr,
+            None => return 0,
+        };
+        self.with_heap(|heap| {
+            let overwritten = match var.t {
+                JsType::JsPtr(ref tag) if in_place => {
+                    match heap.find_id(&var.unique) {
+                        Some(cell) => {
+                            let data = cell.borrow();
+                            if tag.eq_ptr_type(&*data) { alloc::ptr_size(&*data) } else { 0 }
+                        }
+                        None => 0,
+                    }
+                }
+                _ => 0,
+            };
+            heap.bytes_needed(ptr).saturating_sub(overwritten)
+        })
+    }
+
+    /// Declare a variable in the current scope, as `Backend::alloc` does,
+    /// but report running out of memory as `HeapError::OutOfMemory` rather
+    /// than as a failed pointer allocation.
+    pub fn try_alloc(&mut self, var: JsVar, ptr: Option<JsPtrEnum>) -> HeapResult<Binding> {
+        let binding = self.alloc_with_host(var, ptr, None)?;
+        self.verify_after("alloc");
+        Ok(binding)
+    }
+
+    /// Store a variable, as `Backend::store` does, but report running out
+    /// of memory as `HeapError::OutOfMemory` rather than as a failed pointer
+    /// allocation.
+    pub fn try_store(&mut self, var: JsVar, ptr: Option<JsPtrEnum>) -> HeapResult<()> {
+        self.store_var(var, ptr, true)
+    }
+
+    /// Create a weak reference to the heap object behind a binding.
+    pub fn make_weak(&mut self, bnd: &Binding) -> Result<WeakRef> {
+        match self.object_id(bnd)? {
+            Some(id) => Ok(WeakRef::new(id)),
+            None => Err(GcError::PtrAlloc),
+        }
+    }
+
+    /// Return a copy of the data behind a weak reference, or `None` if the
+    /// collector has found its object dead.
+    pub fn deref_weak(&self, weak: &WeakRef) -> Option<JsPtrEnum> {
+        self.alloc_box.lock().unwrap().object(weak.target()).map(|alloc| alloc.borrow().clone())
+    }
+
+    pub fn new_ephemeron_table(&mut self) -> EphemeronId {
+        let id = EphemeronId(self.next_ephemeron_id);
+        self.next_ephemeron_id += 1;
+        self.ephemerons.insert(id, EphemeronTable::new());
+        id
+    }
+
+    /// Look up an ephemeron table. Like every other way into the tables and
+    /// the finalizers, this finishes a pending lazy sweep first, so that the
+    /// entries the last mark found dead have already been cleared.
+    pub fn ephemeron_table(&mut self, id: EphemeronId) -> Option<&EphemeronTable> {
+        self.finish_sweep();
+        self.ephemerons.get(&id)
+    }
+
+    pub fn ephemeron_table_mut(&mut self, id: EphemeronId) -> Option<&mut EphemeronTable> {
+        self.finish_sweep();
+        self.ephemerons.get_mut(&id)
+    }
+
+    /// Associate a value with a key in an ephemeron table, replacing any
+    /// previous value. A pointer value that isn't in the heap yet is
+    /// allocated there; one that is gets a reference of the table's own.
+    pub fn ephemeron_set(&mut self,
+                         id: EphemeronId,
+                         key: &WeakRef,
+                         var: JsVar,
+                         ptr: Option<JsPtrEnum>)
+                         -> Result<()> {
+        self.finish_sweep();
+        let table = match self.ephemerons.get_mut(&id) {
+            Some(table) => table,
+            None => return Err(GcError::PtrAlloc),
+        };
+        self.alloc_box
+            .lock()
+            .unwrap()
+            .with_nursery(&self.nursery, |heap| table.set(key, var, ptr, heap))?;
+        if let Some(unique) = table.value_unique(key) {
+            self.barrier.record(unique);
+        }
+        Ok(())
+    }
+
+    /// Return a copy of the value associated with a key in an ephemeron table.
+    pub fn ephemeron_get(&mut self,
+                         id: EphemeronId,
+                         key: &WeakRef)
+                         -> Option<(JsVar, Option<JsPtrEnum>)> {
+        self.finish_sweep();
+        match self.ephemerons.get(&id) {
+            Some(table) => table.get(key, &*self.alloc_box.lock().unwrap()),
+            None => None,
+        }
+    }
+
+    /// Remove a key and its value from an ephemeron table, returning whether
+    /// it was present.
+    pub fn ephemeron_delete(&mut self, id: EphemeronId, key: &WeakRef) -> bool {
+        self.finish_sweep();
+        match self.ephemerons.get_mut(&id) {
+            Some(table) => table.delete(key, &mut *self.alloc_box.lock().unwrap()),
+            None => false,
+        }
+    }
+
+    /// Destroy an ephemeron table, releasing all of its values.
+    pub fn drop_ephemeron_table(&mut self, id: EphemeronId) -> bool {
+        self.finish_sweep();
+        if let Some(mut table) = self.ephemerons.remove(&id) {
+            table.clear(&mut *self.alloc_box.lock().unwrap());
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Register a finalizer for the object behind a weak reference. When the
+    /// object is collected, a job carrying `held` is queued for the embedder.
+    pub fn register_finalizer(&mut self,
+                              target: &WeakRef,
+                              held: JsVar,
+                              ptr: Option<JsPtrEnum>)
+                              -> Result<()> {
+        self.finish_sweep();
+        let finalizers = &mut self.finalizers;
+        let unique = self.alloc_box
+                         .lock()
+                         .unwrap()
+                         .with_nursery(&self.nursery,
+                                       |heap| finalizers.register(target, held, ptr, heap))?;
+        if let Some(unique) = unique {
+            self.barrier.record(&unique);
+        }
+        Ok(())
+    }
+
+    pub fn unregister_finalizers(&mut self, target: &WeakRef) -> bool {
+        self.finish_sweep();
+        self.finalizers.unregister(target, &mut *self.alloc_box.lock().unwrap())
+    }
+
+    /// Take the finalization jobs queued by previous collections. The embedder
+    /// is expected to run these once the interpreter is at a safe point.
+    pub fn take_finalization_jobs(&mut self) -> Vec<FinalizationJob> {
+        self.finish_sweep();
+        self.finalizers.take_jobs(&mut *self.alloc_box.lock().unwrap())
+    }
+
+    pub fn rename_closure(&mut self, old: &UniqueBinding, new: &UniqueBinding) -> bool {
+        if self.closures.contains_key(old) {
+            let mut scope = self.closures.remove(old).unwrap();
+            scope.tag = ScopeTag::Closure(new.clone());
+            self.closures.insert(new.clone(), scope);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Hold on to the value behind a binding until the returned handle is
+    /// dropped, however long the binding itself lives.
+    pub fn root_handle(&mut self, bnd: &Binding) -> Result<RootHandle> {
+        let id = self.hold(bnd)?;
+        Ok(RootHandle::new(id, &self.handles))
+    }
+
+    /// Open a scope for handles that only need to live as long as it does.
+    pub fn handle_scope(&self) -> HandleScope {
+        HandleScope::new(&self.handles)
+    }
+
+    /// Hold on to the value behind a binding until `scope` is dropped.
+    pub fn scoped_handle(&mut self, scope: &mut HandleScope, bnd: &Binding) -> Result<HandleId> {
+        let id = self.hold(bnd)?;
+        scope.push(id);
+        Ok(id)
+    }
+
+    /// Hold on to the value behind a binding. A pointer is held through a
+    /// heap reference of its own, so the handle keeps the object even if the
+    /// variable is later made to refer to something else.
+    fn hold(&mut self, bnd: &Binding) -> Result<HandleId> {
+        self.finish_sweep();
+        let var = self.lookup(bnd)?.clone();
+        let var = match var.t {
+            JsType::JsPtr(_) => {
+                let alias = JsVar::new(var.t.clone());
+                self.alloc_box.lock().unwrap().alias(alias.unique.clone(), &var.unique)?;
+                self.barrier.record(&alias.unique);
+                alias
+            }
+            _ => var,
+        };
+        Ok(self.handles.hold(var))
+    }
+
+    /// Load the variable behind a binding, along with its data if it's a
+    /// pointer. This is `Backend::load`, for a manager of either kind.
+    pub fn load_var(&self, bnd: &Binding) -> Result<(JsVar, Option<JsPtrEnum>)> {
+        let lookup = || {
+            for scope in self.scopes.iter().rev() {
+                match scope.get_var_copy(bnd) {
+                    Ok(v) => {
+                        return Ok(v);
+                    }
+                    Err(LookupError::FnBoundary) => {
+                        return Err(GcError::Load(bnd.clone()));
+                    }
+                    Err(LookupError::CheckParent) => {}
+                    Err(LookupError::Unreachable) => unreachable!(),
+                }
+            }
+            Err(GcError::Load(bnd.clone()))
+        };
+        match lookup() {
+            Ok(v) => Ok(v),
+            Err(GcError::Load(bnd)) => {
+                self.global_scope()
+                    .get_var_copy(&bnd)
+                    .map_err(|_| GcError::Load(bnd.clone()))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Copy the value held by a handle, along with its data if it's a
+    /// pointer. Returns `None` once the handle has been released.
+    pub fn load_handle(&self, id: HandleId) -> Option<(JsVar, Option<JsPtrEnum>)> {
+        let var = match self.handles.get(id) {
+            Some(var) => var,
+            None => return None,
+        };
+        let ptr = match var.t {
+            JsType::JsPtr(_) => {
+                let heap = self.alloc_box.lock().unwrap();
+                heap.find_id(&var.unique).map(|alloc| alloc.borrow().clone())
+            }
+            _ => None,
+        };
+        Some((var, ptr))
+    }
+
+    /// Register a provider of roots from the embedder's own data structures.
+    /// It's asked for its roots at the start of every mark until removed.
+    pub fn add_root_provider(&mut self, provider: Arc<RootProvider>) -> RootProviderId {
+        let id = RootProviderId(self.next_root_provider_id);
+        self.next_root_provider_id += 1;
+        self.root_providers.push((id, provider));
+        id
+    }
+
+    /// Unregister a root provider, returning it if it was registered.
+    pub fn remove_root_provider(&mut self, id: RootProviderId) -> Option<Arc<RootProvider>> {
+        match self.root_providers.iter().position(|&(other, _)| other == id) {
+            Some(index) => Some(self.root_providers.remove(index).1),
+            None => None,
+        }
+    }
+
+    /// Declare a pointer variable in the current scope whose object carries a
+    /// host object. The host object is traced along with the object, and
+    /// its `on_sweep` hook runs when the object is swept.
+    pub fn alloc_host(&mut self,
+                      var: JsVar,
+                      ptr: JsPtrEnum,
+                      host: Box<HostObject>)
+                      -> Result<Binding> {
+        match var.t {
+            JsType::JsPtr(ref tag) if tag.eq_ptr_type(&ptr) => {}
+            _ => return Err(GcError::PtrAlloc),
+        }
+        if self.alloc_box.lock().unwrap().is_allocated(&var.unique) {
+            return Err(GcError::PtrAlloc);
+        }
+        let binding = self.alloc_with_host(var, Some(ptr), Some(host))?;
+        self.verify_after("alloc_host");
+        Ok(binding)
+    }
+
+    /// Declare a variable in the current scope, allocating its pointer if it
+    /// has one and it isn't in the heap yet, and attaching `host` to the new
+    /// object.
+    fn alloc_with_host(&mut self,
+                       var: JsVar,
+                       ptr: Option<JsPtrEnum>,
+                       host: Option<Box<HostObject>>)
+                       -> HeapResult<Binding> {
+        if ptr.is_some() {
+            self.sweep_step();
+            self.policy.record_alloc();
+        }
+        let binding = var.binding.clone();
+        let is_allocated = self.alloc_box.lock().unwrap().is_allocated(&var.unique);
+
+        // If the ptr is already allocated in the heap, just push it onto the stack
+        if is_allocated && ptr.is_some() {
+            self.curr_scope_mut().bind_var(var);
+        } else {
+            let unique = var.unique.clone();
+            let bytes = ptr.as_ref().map(alloc::ptr_size);
+            // The object's properties are still rooted in the nursery, so
+            // an emergency collection can't sweep them out from under us.
+            let needed = self.bytes_needed(&var, &ptr, false);
+            self.reserve(needed)?;
+            let nursery = self.nursery.clone();
+            self.curr_scope_mut().push_var(var, ptr, &nursery)?;
+            // The host object has to be attached before anything can
+            // collect, or the objects only it refers to would be swept.
+            if let Some(host) = host {
+                self.alloc_box.lock().unwrap().attach_host(&unique, host)?;
+            }
+            if let Some(bytes) = bytes {
+                self.log_event(|| {
+                    GcEvent::Alloc {
+                        unique: unique.clone(),
+                        bytes: bytes,
+                    }
+                });
+            }
+        }
+        self.stress_collect();
+        Ok(binding)
+    }
+
+    /// Run `f` on the host object carried by the object behind a binding.
+    /// Returns `None` if the object doesn't carry one. The heap stays
+    /// locked while `f` runs, so `f` mustn't call back into the
+    /// `ScopeManager`.
+    pub fn with_host<R, F>(&self, bnd: &Binding, f: F) -> Result<Option<R>>
+        where F: FnOnce(&mut HostObject) -> R
+    {
+        let unique = self.lookup(bnd)?.unique.clone();
+        let heap = self.alloc_box.lock().unwrap();
+        let result = heap.host(&unique).map(|host| f(&mut **host.borrow_mut()));
+        // The host object may now refer to values a background mark hasn't
+        // seen.
+        self.barrier.record(&unique);
+        Ok(result)
+    }
+
+    /// Copy the data of an object, e.g. one a host object or root provider
+    /// holds, or `None` if it has been collected.
+    pub fn load_object(&self, id: ObjectId) -> Option<JsPtrEnum> {
+        self.alloc_box.lock().unwrap().object(id).map(|alloc| alloc.borrow().clone())
+    }
+
+    /// Like `load`, but rather than copying the data behind a pointer out of
+    /// the heap, return a `CellRef` through which it can be read and mutated
+    /// in place.
+    pub fn load_ref(&self, bnd: &Binding) -> Result<(JsVar, Option<CellRef<N>>)> {
+        let var = self.lookup(bnd)?.clone();
+        let cell = match var.t {
+            JsType::JsPtr(ref tag) => {
+                Some(CellRef::new(&self.alloc_box,
+                                  var.unique.clone(),
+                                  tag.clone(),
+                                  &self.barrier,
+                                  &self.nursery))
+            }
+            _ => None,
+        };
+        Ok((var, cell))
+    }
+
+    /// Copy one property of the object behind a binding out of the heap,
+    /// along with the data it points to, without copying the rest of the
+    /// object. Returns `None` if the object has no such property.
+    pub fn get_property(&self,
+                        bnd: &Binding,
+                        key: &JsKey)
+                        -> Result<Option<(JsVar, Option<JsPtrEnum>)>> {
+        let obj = self.lookup_obj(bnd)?;
+        let heap = self.alloc_box.lock().unwrap();
+        let var = match heap.find_id(&obj) {
+            Some(cell) => {
+                match *cell.borrow() {
+                    JsPtrEnum::JsObj(ref obj_struct) => obj_struct.dict.get(key).cloned(),
+                    _ => None,
+                }
+            }
+            None => None,
+        };
+        let var = match var {
+            Some(var) => var,
+            None => return Ok(None),
+        };
+        let ptr = match var.t {
+            JsType::JsPtr(_) => heap.find_id(&var.unique).map(|cell| cell.borrow().clone()),
+            _ => None,
+        };
+        Ok(Some((var, ptr)))
+    }
+
+    /// Set a property of the object behind a binding, in place. If the value
+    /// is a pointer, its data goes into the heap, but not as a root: the cell
+    /// lives for as long as the object or anything else refers to it. A
+    /// pointer that's already allocated, e.g. a variable loaded with `load`,
+    /// may be passed without data to make the property refer to the same
+    /// object. Passed with data, it gets a new object holding the data, and
+    /// the object the variable refers to is left as it was.
+    pub fn set_property(&mut self,
+                        bnd: &Binding,
+                        key: JsKey,
+                        var: JsVar,
+                        ptr: Option<JsPtrEnum>)
+                        -> Result<()> {
+        self.sweep_step();
+        let obj = match self.lookup_obj(bnd) {
+            Ok(obj) => obj,
+            Err(_) => return Err(GcError::Store(var, ptr)),
+        };
+        let was_allocated = self.alloc_box.lock().unwrap().is_allocated(&var.unique);
+        let mut var = var;
+        let bytes = match (&var.t, &ptr) {
+            (&JsType::JsPtr(ref tag), &Some(ref ptr)) if tag.eq_ptr_type(ptr) => {
+                self.with_heap(|heap| heap.bytes_needed(ptr))
+            }
+            (&JsType::JsPtr(_), &None) if was_allocated => 0,
+            (&JsType::JsPtr(_), _) |
+            (_, &Some(_)) => return Err(GcError::PtrAlloc),
+            (_, &None) => 0,
+        };
+        self.reserve(bytes)?;
+        let new_cell = {
+            let mut heap = self.alloc_box.lock().unwrap();
+            heap.with_nursery(&self.nursery, |heap| -> Result<Option<(UniqueBinding, usize)>> {
+                if was_allocated {
+                    // The property gets a reference of its own, so that it
+                    // doesn't change along with the variable it came from.
+                    let prop = JsVar::new(var.t.clone());
+                    if ptr.is_none() {
+                        heap.alias(prop.unique.clone(), &var.unique)?;
+                        heap.condemn(prop.unique.clone())?;
+                    }
+                    var = prop;
+                }
+                match ptr {
+                    Some(ptr) => {
+                        let bytes = alloc::ptr_size(&ptr);
+                        heap.alloc(var.unique.clone(), ptr)?;
+                        heap.condemn(var.unique.clone())?;
+                        Ok(Some((var.unique.clone(), bytes)))
+                    }
+                    None => Ok(None),
+                }
+            })?
+        };
+        self.alloc_box.lock().unwrap().mutate(&obj, move |data| {
+            if let JsPtrEnum::JsObj(ref mut obj_struct) = *data {
+                obj_struct.dict.insert(key, var);
+            }
+        });
+        // The object may now point to a cell a background mark hasn't seen.
+        self.barrier.record(&obj);
+        if let Some((unique, bytes)) = new_cell {
+            self.policy.record_alloc();
+            self.log_event(|| {
+                GcEvent::Alloc {
+                    unique: unique.clone(),
+                    bytes: bytes,
+                }
+            });
+        }
+        self.stress_collect();
+        self.verify_after("set_property");
+        Ok(())
+    }
+
+    /// Delete a property of the object behind a binding, in place, and
+    /// return whether it was there. Any cell the property pointed to is
+    /// collected as usual once nothing else refers to it.
+    pub fn delete_property(&mut self, bnd: &Binding, key: &JsKey) -> Result<bool> {
+        let obj = self.lookup_obj(bnd)?;
+        let deleted = self.alloc_box.lock().unwrap().mutate(&obj, |data| {
+            match *data {
+                JsPtrEnum::JsObj(ref mut obj_struct) => obj_struct.dict.remove(key).is_some(),
+                _ => false,
+            }
+        });
+        self.verify_after("delete_property");
+        Ok(deleted.unwrap_or(false))
+    }
+
+    /// Find the heap cell of the object behind a binding.
+    fn lookup_obj(&self, bnd: &Binding) -> Result<UniqueBinding> {
+        let var = self.lookup(bnd)?;
+        match var.t {
+            JsType::JsPtr(JsPtrTag::JsObj) => Ok(var.unique.clone()),
+            _ => Err(GcError::Load(bnd.clone())),
+        }
+    }
+
+    /// Declare a variable in the current scope that refers to the same object
+    /// as `target`, as in `let b = a`. The variable takes on the type of
+    /// `target`.
+    pub fn alloc_ref(&mut self, var: JsVar, target: &Binding) -> Result<Binding> {
+        self.finish_sweep();
+        let target = self.lookup(target)?.clone();
+        self.bind_ref(var, target, "alloc_ref")
+    }
+
+    /// Like `alloc_ref`, but the variable refers to the object held by a
+    /// handle, e.g. to call a callback the embedder kept hold of. Fails with
+    /// `PtrAlloc` if the handle has been released.
+    pub fn alloc_handle_ref(&mut self, var: JsVar, id: HandleId) -> Result<Binding> {
+        self.finish_sweep();
+        let target = match self.handles.get(id) {
+            Some(target) => target,
+            None => return Err(GcError::PtrAlloc),
+        };
+        self.bind_ref(var, target, "alloc_handle_ref")
+    }
+
+    fn bind_ref(&mut self, var: JsVar, target: JsVar, op: &str) -> Result<Binding> {
+        let mut var = var;
+        self.alloc_box.lock().unwrap().alias(var.unique.clone(), &target.unique)?;
+        self.barrier.record(&var.unique);
+        var.t = target.t;
+        let binding = var.binding.clone();
+        self.curr_scope_mut().bind_var(var);
+        self.stress_collect();
+        self.verify_after(op);
+        Ok(binding)
+    }
+
+    /// Make an existing variable refer to the same object as `target`, as in
+    /// `b = a`. Whatever the variable referred to before is left to anything
+    /// else that still refers to it.
+    pub fn store_ref(&mut self, bnd: &Binding, target: &Binding) -> Result<()> {
+        self.finish_sweep();
+        let target = self.lookup(target)?.clone();
+        let mut var = self.lookup(bnd)?.clone();
+        let index = self.scope_index(bnd).expect("Found a variable outside every scope");
+        self.alloc_box.lock().unwrap().alias(var.unique.clone(), &target.unique)?;
+        self.barrier.record(&var.unique);
+        var.t = target.t;
+        self.scopes[index].bind_var(var);
+        self.stress_collect();
+        self.verify_after("store_ref");
+        Ok(())
+    }
+
+    /// Give an existing variable a new value, as in `b = "b"`. Unlike
+    /// `store`, a pointer always gets a new object of its own, even over one
+    /// of the same type, so whatever the variable referred to before is left
+    /// as it was, to anything else that still refers to it. `store` is for
+    /// writing back an object loaded and changed through the variable.
+    pub fn rebind(&mut self, var: JsVar, ptr: Option<JsPtrEnum>) -> HeapResult<()> {
+        self.store_var(var, ptr, false)
+    }
+
+    /// Store a variable into the scope it was declared in. Stored `in_place`,
+    /// a pointer of the same type as the variable's current object is
+    /// written over it; otherwise it gets a new object.
+    fn store_var(&mut self,
+                 var: JsVar,
+                 ptr: Option<JsPtrEnum>,
+                 in_place: bool)
+                 -> HeapResult<()> {
+        self.sweep_step();
+        // Make room for the stored value first, so that running out of
+        // memory leaves the variable as it was.
+        let bytes = self.bytes_needed(&var, &ptr, in_place);
+        self.reserve(bytes)?;
+        let unique = var.unique.clone();
+        let is_ptr = ptr.is_some();
+        // Storing a pointer creates or replaces a root, and storing anything
+        // else over a pointer removes one.
+        let changes_root = is_ptr || self.alloc_box.lock().unwrap().is_allocated(&unique);
+        let nursery = self.nursery.clone();
+        let (mut var, mut ptr) = (var, ptr);
+        let lookup = {
+            let mut res = Err(GcError::Store(var.clone(), ptr.clone()));
+            for ref mut scope in self.scopes.iter_mut().rev() {
+                let stored = if in_place {
+                    scope.update_var(var, ptr, &nursery)
+                } else {
+                    scope.replace_var(var, ptr, &nursery)
+                };
+                match stored {
+                    Ok(()) => {
+                        res = Ok(());
+                        break;
+                    }
+                    Err(StoreError::CheckParent(v, p)) => {
+                        var = v;
+                        ptr = p;
+                    }
+                    Err(StoreError::FnBoundary(v, p)) => {
+                        res = Err(GcError::Store(v, p));
+                        break;
+                    }
+                    Err(StoreError::PtrTypeMismatch) |
+                    Err(StoreError::BadStore) => {
+                        res = Err(GcError::PtrAlloc);
+                        break;
+                    }
+                }
+            }
+            res
+        };
+        match lookup {
+            Ok(()) => {}
+            Err(GcError::Store(var, ptr)) => {
+                let res = if in_place {
+                    self.global_scope_mut().update_var(var.clone(), ptr.clone(), &nursery)
+                } else {
+                    self.global_scope_mut().replace_var(var.clone(), ptr.clone(), &nursery)
+                };
+                res.map_err(|_| GcError::Store(var, ptr))?
+            }
+            Err(err) => return Err(HeapError::from(err)),
+        }
+        if changes_root {
+            self.log_event(|| {
+                GcEvent::Store {
+                    unique: unique.clone(),
+                    is_ptr: is_ptr,
+                }
+            });
+        }
+        self.stress_collect();
+        self.verify_after("store");
+        Ok(())
+        // let res = self.curr_scope_mut().update_var(var, ptr);
+        // if let Err(GcError::Store(var, ptr)) = res {
+        // self.global_scope_mut().update_var(var, ptr)
+        // } else {
+        // res
+        // }
+    }
+
+    /// The identity of the object behind a binding, or `None` if it holds a
+    /// primitive. Two bindings are `===` as objects exactly when they have
+    /// the same id.
+    pub fn object_id(&self, bnd: &Binding) -> Result<Option<ObjectId>> {
+        let var = self.lookup(bnd)?;
+        match var.t {
+            JsType::JsPtr(_) => Ok(self.alloc_box.lock().unwrap().object_id(&var.unique)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Find the variable behind a binding, searching the scopes the same way
+    /// `load` does.
+    fn lookup(&self, bnd: &Binding) -> Result<&JsVar> {
+        match self.scope_index(bnd) {
+            Some(index) => {
+                self.scopes[index].get_var(bnd).map_err(|_| GcError::Load(bnd.clone()))
+            }
+            None => Err(GcError::Load(bnd.clone())),
+        }
+    }
+
+    /// The index of the scope a binding would be loaded from.
+    fn scope_index(&self, bnd: &Binding) -> Option<usize> {
+        for (index, scope) in self.scopes.iter().enumerate().rev() {
+            match scope.get_var(bnd) {
+                Ok(_) => return Some(index),
+                Err(LookupError::FnBoundary) => break,
+                Err(LookupError::CheckParent) => {}
+                Err(LookupError::Unreachable) => unreachable!(),
+            }
+        }
+        if self.global_scope().get_var(bnd).is_ok() {
+            Some(0)
+        } else {
+            None
+        }
+    }
+}
+
+impl Backend for ScopeManager {
+    /// Running out of memory under the heap limit is reported as
+    /// `GcError::PtrAlloc`; use `try_alloc` to tell it apart.
+    fn alloc(&mut self, var: JsVar, ptr: Option<JsPtrEnum>) -> Result<Binding> {
+        Ok(self.try_alloc(var, ptr)?)
+    }
+    /// Try to load the variable behind a binding
+    fn load(&mut self, bnd: &Binding) -> Result<(JsVar, Option<JsPtrEnum>)> {
+        self.load_var(bnd)
+    }
+
+    /// Running out of memory under the heap limit is reported as
+    /// `GcError::PtrAlloc`; use `try_store` to tell it apart.
+    fn store(&mut self, var: JsVar, ptr: Option<JsPtrEnum>) -> Result<()> {
+        Ok(self.try_store(var, ptr)?)
+    }
+
+    /// The heap that `JsObjStruct` allocates object properties into. They're
+    /// moved into `alloc_box` when the object is allocated or stored.
+    fn get_alloc_box(&self) -> Rc<RefCell<Nursery>> {
+        self.nursery.clone()
+    }
+}
+
+/// Add an edge from a snapshot node to each heap cell on a scope's stack,
+/// named after the variable that points to it.
+fn add_scope_edges(snapshot: &mut HeapSnapshot, from: usize, scope: &Scope) {
+    for var in scope.vars() {
+        if let JsType::JsPtr(_) = var.t {
+            if let Some(to) = snapshot.cell(&var.unique) {
+                snapshot.add_edge(from, EdgeType::Context, &format!("{:?}", var.binding), to);
+            }
+        }
+    }
+}
+
+pub fn init_gc() -> ScopeManager {
+    let alloc_box = Arc::new(Mutex::new(AllocBox::new()));
+    ScopeManager::new(alloc_box)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc;
+
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use jsrs_common::ast::Exp;
+    use jsrs_common::backend::Backend;
+    use jsrs_common::gc_error::{GcError, Result};
+    use jsrs_common::test_utils;
+    use jsrs_common::types::js_var::{JsKey, JsPtrEnum, JsType, JsVar};
+    use jsrs_common::types::binding::{Binding, UniqueBinding};
+
+    use host::SweepCounter;
+
+    #[test]
+    fn test_push_closure_scope() {
+        let alloc_box = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        mgr.push_scope(&Exp::Undefined);
+        let (fn_var, fn_ptr) = test_utils::make_fn(&None, &Vec::new());
+        let unique = fn_var.unique.clone();
+        mgr.alloc(fn_var, Some(fn_ptr)).unwrap();
+        mgr.pop_scope(Some(unique.clone()), GcYield::Never).unwrap();
+        assert_eq!(mgr.closures.len(), 1);
+        mgr.push_closure_scope(&unique).unwrap();
+        assert_eq!(mgr.closures.len(), 0);
+        mgr.pop_scope(None, GcYield::Never).unwrap();
+        assert_eq!(mgr.closures.len(), 1);
+    }
+
+    #[test]
+    fn test_pop_scope() {
+        let alloc_box = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        mgr.push_scope(&Exp::Undefined);
+        assert_eq!(mgr.scopes.len(), 2);
+        mgr.pop_scope(None, GcYield::Never).unwrap();
+        assert_eq!(mgr.scopes.len(), 1);
+    }
+
+    #[test]
+    fn test_pop_scope_fail() {
+        let alloc_box = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let res = mgr.pop_scope(None, GcYield::Never);
+        assert!(res.is_err());
+        assert!(matches!(res, Err(GcError::Scope)));
+    }
+
+    #[test]
+    fn test_alloc() {
+        let alloc_box = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        mgr.push_scope(&Exp::Undefined);
+        mgr.alloc(test_utils::make_num(2.), None).unwrap();
+        assert!(mgr.alloc_box.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_load() {
+        let alloc_box = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let x = test_utils::make_num(1.);
+        let x_bnd = mgr.alloc(x, None).unwrap();
+        let load = mgr.load(&x_bnd);
+        assert!(load.is_ok());
+        let load = load.unwrap();
+        match load.0.t {
+            JsType::JsNum(n) => assert!(f64::abs(n - 1.) < 0.0001),
+            _ => unreachable!(),
+        }
+        assert!(load.1.is_none());
+    }
+
+    #[test]
+    fn test_load_fail() {
+        let alloc_box = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let bnd = Binding::new("".to_owned());
+        let res = mgr.load(&bnd);
+        assert!(res.is_err());
+        assert!(matches!(res, Err(GcError::Load(_))));
+        if let Err(GcError::Load(res_bnd)) = res {
+            assert_eq!(bnd, res_bnd);
+        }
+    }
+
+    #[test]
+    fn test_store() {
+        let alloc_box = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        mgr.push_scope(&Exp::Undefined);
+
+        let x = test_utils::make_num(1.);
+        let x_bnd = mgr.alloc(x, None).unwrap();
+
+        let (mut var, _) = mgr.load(&x_bnd).unwrap();
+        var.t = JsType::JsNum(2.);
+
+        assert!(mgr.store(var, None).is_ok());
+    }
+
+    /// A value of each kind a variable can hold: a primitive, and each type of
+    /// pointer.
+    fn make_kind(kind: usize, mgr: &ScopeManager) -> (JsType, Option<JsPtrEnum>) {
+        let (var, ptr) = match kind {
+            0 => (test_utils::make_num(1.), None),
+            1 => {
+                let (var, ptr) = test_utils::make_str("s");
+                (var, Some(ptr))
+            }
+            2 => {
+                let (var, ptr) = test_utils::make_obj(vec![], mgr.get_alloc_box());
+                (var, Some(ptr))
+            }
+            _ => {
+                let (var, ptr) = test_utils::make_fn(&None, &Vec::new());
+                (var, Some(ptr))
+            }
+        };
+        (var.t, ptr)
+    }
+
+    #[test]
+    fn test_retyping() {
+        for from in 0..4 {
+            for to in 0..4 {
+                let alloc_box = alloc::make_alloc_box();
+                let mut mgr = ScopeManager::new(alloc_box);
+                mgr.push_scope(&Exp::Undefined);
+                let mut x = test_utils::make_num(0.);
+                let (t, ptr) = make_kind(from, &mgr);
+                x.t = t;
+                let x_bnd = mgr.alloc(x, ptr).unwrap();
+                // Another variable shares x's object, if it has one
+                let y_bnd = if from > 0 {
+                    Some(mgr.alloc_ref(test_utils::make_num(0.), &x_bnd).unwrap())
+                } else {
+                    None
+                };
+                let y_id = y_bnd.as_ref().map(|bnd| mgr.object_id(bnd).unwrap());
+
+                let (mut x, _) = mgr.load(&x_bnd).unwrap();
+                let (t, ptr) = make_kind(to, &mgr);
+                let is_ptr = ptr.is_some();
+                x.t = t;
+                assert!(mgr.store(x, ptr).is_ok(),
+                        "storing kind {} over kind {} failed",
+                        to,
+                        from);
+                mgr.collect(CollectionKind::Full);
+
+                match mgr.load(&x_bnd).unwrap() {
+                    (JsVar { t: JsType::JsPtr(ref tag), .. }, Some(ref ptr)) => {
+                        assert!(tag.eq_ptr_type(ptr))
+                    }
+                    (JsVar { t: JsType::JsPtr(_), .. }, None) => unreachable!(),
+                    (_, ptr) => assert!(ptr.is_none()),
+                }
+                // Only storing a pointer of the same type writes through to
+                // the shared object
+                if let Some(ref y_bnd) = y_bnd {
+                    assert_eq!(mgr.object_id(y_bnd).unwrap(), y_id.unwrap());
+                    let shared = mgr.object_id(&x_bnd).unwrap() == y_id.unwrap();
+                    assert_eq!(shared, from == to);
+                }
+                let objects = y_bnd.iter().count() + (is_ptr && from != to) as usize;
+                assert_eq!(mgr.alloc_box.lock().unwrap().len(), objects);
+                assert!(mgr.verify().is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn test_store_fail() {
+        let alloc_box = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+        let x = test_utils::make_num(1.);
+        assert!(mgr.store(x, None).is_err());
+    }
+
+    #[test]
+    fn test_store_to_parent_scope() {
+        let alloc_box = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
+
+        // Avoids having just the global scope available
+        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+        let x = test_utils::make_num(1.);
+        let x_bnd = mgr.alloc(x, None).unwrap();
+        let copy = mgr.load(&x_bnd);
+        let (mut x, _) = copy.unwrap();
+
+        mgr.push_scope(&Exp::Undefined);
+        match x.t {
+            JsType::JsNum(_) => x.t = JsType::JsNum(1.),
+            _ => unreachable!(),
+        };
+        assert!(mgr.store(x, None).is_ok())
+    }
+
+    #[test]
+    fn test_store_to_parent_scope_across_fn_boundary() {
+        let alloc_box = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(alloc_box);
 
         // Avoids having just the global scope available
         mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
         let x = test_utils::make_num(1.);
         let x_bnd = mgr.alloc(x, None).unwrap();
         let copy = mgr.load(&x_bnd);
-        let (mut x, _) = copy.unwrap();
+        let (mut x, _) = copy.unwrap();
+
+        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+        match x.t {
+            JsType::JsNum(_) => x.t = JsType::JsNum(1.),
+            _ => unreachable!(),
+        };
+        assert!(mgr.store(x, None).is_err())
+    }
+
+    #[test]
+    fn test_load_from_parent_scope_across_fn_boundary() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+
+        // Avoids having just the global scope available
+        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+        let (x, x_ptr) = test_utils::make_str("x");
+        let x_bnd = mgr.alloc(x, Some(x_ptr)).unwrap();
+
+        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+        let copy = mgr.load(&x_bnd);
+
+        assert!(copy.is_err());
+        assert!(matches!(copy, Err(GcError::Load(_))));
+    }
+
+    #[test]
+    fn test_load_from_parent_scope_no_fn_call() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+
+        // Avoids having just the global scope available
+        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
+        let (x, x_ptr) = test_utils::make_str("x");
+        let x_bnd = mgr.alloc(x, Some(x_ptr)).unwrap();
+
+        mgr.push_scope(&Exp::Undefined);
+        let copy = mgr.load(&x_bnd);
+
+        assert!(copy.is_ok());
+        let (var_copy, ptr_copy) = copy.unwrap();
+        assert!(matches!(var_copy, JsVar { t: JsType::JsPtr(_), .. }));
+        assert!(ptr_copy.is_some());
+    }
+
+    #[test]
+    fn test_transfer_stack_with_yield() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        // Make some scopes
+        mgr.push_scope(&Exp::Undefined);
+        {
+            // Push a child scope
+            mgr.push_scope(&Exp::Undefined);
+            // Allocate some non-root variables (numbers)
+            mgr.alloc(test_utils::make_num(0.), None).unwrap();
+            mgr.alloc(test_utils::make_num(1.), None).unwrap();
+            mgr.alloc(test_utils::make_num(2.), None).unwrap();
+
+            // Make a string to put into an object
+            // (so it's heap-allocated and we can lose its ref from the object)
+            let (var, ptr) = test_utils::make_str("test");
+
+            // Create an obj of { true: 1.0, false: heap("test") }
+            let kvs = vec![(JsKey::JsSym("true".to_string()),
+                            test_utils::make_num(1.),
+                            None),
+                           (JsKey::JsSym("false".to_string()), var, Some(ptr))];
+            let (var, ptr) = test_utils::make_obj(kvs, mgr.get_alloc_box());
+
+            // Push the obj into the current scope
+            let bnd = mgr.alloc(var, Some(ptr)).unwrap();
+            // The heap should now have 2 things in it: an object and a string
+            assert_eq!(mgr.alloc_box.lock().unwrap().len(), 2);
+
+            // Replace the string in the object with something else so it's no longer live
+            let copy = mgr.load(&bnd);
+            let (var_cp, mut ptr_cp) = copy.unwrap();
+            let key = JsKey::JsSym("false".to_string());
+            match *&mut ptr_cp {
+                Some(JsPtrEnum::JsObj(ref mut obj)) => {
+                    obj.add_key(&var_cp.unique,
+                                key,
+                                test_utils::make_num(-1.),
+                                None,
+                                &mut *(mgr.get_alloc_box().borrow_mut()));
+                }
+                _ => unreachable!(),
+            }
+            mgr.store(var_cp, ptr_cp).unwrap();
+            // The heap should still have 2 things in it: an object and a string
+            assert_eq!(mgr.alloc_box.lock().unwrap().len(), 2);
+
+            // Kill the current scope & give its refs to the parent,
+            // allowing the GC to kick in beforehand.
+            mgr.pop_scope(None, GcYield::Forced).unwrap();
+        }
+        // The object we created above should still exist
+        assert_eq!(mgr.curr_scope().len(), 1);
+        // But the string it had allocated shouldn't, since we leaked it into the void
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_load_ref() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.push_scope(&Exp::Undefined);
+        let n_bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        let kvs = vec![(JsKey::JsSym("n".to_string()), test_utils::make_num(1.), None)];
+        let (obj, obj_ptr) = test_utils::make_obj(kvs, mgr.get_alloc_box());
+        let obj_bnd = mgr.alloc(obj, Some(obj_ptr)).unwrap();
+
+        let (_, cell) = mgr.load_ref(&n_bnd).unwrap();
+        assert!(cell.is_none());
+        assert!(mgr.load_ref(&Binding::new("nope".to_owned())).is_err());
+
+        // Add a property to the object in place
+        let (obj, cell) = mgr.load_ref(&obj_bnd).unwrap();
+        let cell = cell.unwrap();
+        let (s, s_ptr) = test_utils::make_str("s");
+        let nursery = mgr.get_alloc_box();
+        cell.with_mut(|data| {
+                match *data {
+                    JsPtrEnum::JsObj(ref mut obj_struct) => {
+                        obj_struct.add_key(&obj.unique,
+                                           JsKey::JsSym("s".to_string()),
+                                           s,
+                                           Some(s_ptr),
+                                           &mut *nursery.borrow_mut());
+                    }
+                    _ => unreachable!(),
+                }
+            })
+            .unwrap();
+        let len = cell.with(|data| {
+                match *data {
+                    JsPtrEnum::JsObj(ref obj_struct) => obj_struct.dict.len(),
+                    _ => unreachable!(),
+                }
+            })
+            .unwrap();
+        assert_eq!(len, 2);
+        // The new property was moved into the heap, and survives collection
+        mgr.collect(CollectionKind::Full);
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 2);
+        match mgr.load(&obj_bnd).unwrap() {
+            (_, Some(JsPtrEnum::JsObj(ref obj_struct))) => assert_eq!(obj_struct.dict.len(), 2),
+            _ => unreachable!(),
+        }
+
+        // The handle doesn't keep the object alive
+        let (mut obj, _) = mgr.load(&obj_bnd).unwrap();
+        obj.t = JsType::JsNum(0.);
+        mgr.store(obj, None).unwrap();
+        mgr.collect(CollectionKind::Full);
+        assert_eq!(cell.with(|_| ()), None);
+    }
+
+    #[test]
+    fn test_load_ref_keeps_type() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.push_scope(&Exp::Undefined);
+        let (obj, obj_ptr) = test_utils::make_obj(vec![], mgr.get_alloc_box());
+        let obj_bnd = mgr.alloc(obj, Some(obj_ptr)).unwrap();
+        let (_, cell) = mgr.load_ref(&obj_bnd).unwrap();
+        let cell = cell.unwrap();
+
+        // Changing the type is refused, and leaves the data as it was
+        let (_, s_ptr) = test_utils::make_str("s");
+        assert!(cell.with_mut(move |data| *data = s_ptr).is_none());
+        assert!(matches!(mgr.load(&obj_bnd), Ok((_, Some(JsPtrEnum::JsObj(_))))));
+        assert!(mgr.verify().is_empty());
+
+        // Changes that keep it go through
+        assert_eq!(cell.with_mut(|_| 1), Some(1));
+    }
+
+    #[test]
+    fn test_properties() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.push_scope(&Exp::Undefined);
+        let n_bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        let kvs = vec![(JsKey::JsSym("n".to_string()), test_utils::make_num(1.), None)];
+        let (obj, obj_ptr) = test_utils::make_obj(kvs, mgr.get_alloc_box());
+        let obj_bnd = mgr.alloc(obj, Some(obj_ptr)).unwrap();
+        let n_key = JsKey::JsSym("n".to_string());
+        let s_key = JsKey::JsSym("s".to_string());
+
+        match mgr.get_property(&obj_bnd, &n_key).unwrap() {
+            Some((JsVar { t: JsType::JsNum(n), .. }, None)) => assert!(f64::abs(n - 1.) < 0.0001),
+            _ => unreachable!(),
+        }
+        assert!(mgr.get_property(&obj_bnd, &s_key).unwrap().is_none());
+        assert!(mgr.get_property(&n_bnd, &n_key).is_err());
+
+        // A new pointer property is allocated, but isn't a root
+        let (s, s_ptr) = test_utils::make_str("s");
+        mgr.set_property(&obj_bnd, s_key.clone(), s, Some(s_ptr)).unwrap();
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 2);
+        match mgr.get_property(&obj_bnd, &s_key).unwrap() {
+            Some((_, Some(JsPtrEnum::JsStr(ref s)))) => assert_eq!(s.text, "s"),
+            _ => unreachable!(),
+        }
+        mgr.collect(CollectionKind::Full);
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 2);
+        mgr.set_property(&obj_bnd, s_key.clone(), test_utils::make_num(2.), None).unwrap();
+        mgr.collect(CollectionKind::Full);
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 1);
+
+        // A property can alias a cell a variable points to, which stays a root
+        let (y, y_ptr) = test_utils::make_str("y");
+        let y_unique = y.unique.clone();
+        let y_key = JsKey::JsSym("y".to_string());
+        mgr.alloc(y.clone(), Some(y_ptr)).unwrap();
+        mgr.set_property(&obj_bnd, y_key.clone(), y.clone(), None).unwrap();
+        assert!(mgr.delete_property(&obj_bnd, &y_key).unwrap());
+        assert!(!mgr.delete_property(&obj_bnd, &y_key).unwrap());
+        mgr.collect(CollectionKind::Full);
+        assert!(mgr.alloc_box.lock().unwrap().is_allocated(&y_unique));
+
+        // Data passed along with an allocated pointer goes into a new object
+        let (_, y_ptr) = test_utils::make_str("new y");
+        mgr.set_property(&obj_bnd, y_key.clone(), y, Some(y_ptr)).unwrap();
+        match mgr.get_property(&obj_bnd, &y_key).unwrap() {
+            Some((prop, Some(JsPtrEnum::JsStr(ref s)))) => {
+                assert_eq!(s.text, "new y");
+                assert!(prop.unique != y_unique);
+            }
+            _ => unreachable!(),
+        }
+        match *mgr.alloc_box.lock().unwrap().find_id(&y_unique).unwrap().borrow() {
+            JsPtrEnum::JsStr(ref s) => assert_eq!(s.text, "y"),
+            _ => unreachable!(),
+        }
+        let (z, _) = test_utils::make_str("z");
+        assert!(mgr.set_property(&obj_bnd, y_key.clone(), z, None).is_err());
+        match mgr.set_property(&n_bnd, y_key, test_utils::make_num(0.), None) {
+            Err(GcError::Store(..)) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_reference_semantics() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.push_scope(&Exp::Undefined);
+        let x_bnd = mgr.alloc(test_utils::make_num(0.), None).unwrap();
+        assert_eq!(mgr.object_id(&x_bnd).unwrap(), None);
+
+        mgr.push_scope(&Exp::Undefined);
+        let (a, a_ptr) = test_utils::make_obj(vec![], mgr.get_alloc_box());
+        let a_bnd = mgr.alloc(a, Some(a_ptr)).unwrap();
+        let b_bnd = mgr.alloc_ref(test_utils::make_num(0.), &a_bnd).unwrap();
+        let id = mgr.object_id(&a_bnd).unwrap();
+        assert!(id.is_some());
+        assert_eq!(mgr.object_id(&b_bnd).unwrap(), id);
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 1);
+
+        // Mutation through one alias is visible through the other
+        let key = JsKey::JsSym("k".to_string());
+        mgr.set_property(&b_bnd, key.clone(), test_utils::make_num(1.), None).unwrap();
+        assert!(mgr.get_property(&a_bnd, &key).unwrap().is_some());
+
+        // Reassigning one alias leaves the other alone
+        let (c, c_ptr) = test_utils::make_obj(vec![], mgr.get_alloc_box());
+        let c_bnd = mgr.alloc(c, Some(c_ptr)).unwrap();
+        mgr.store_ref(&b_bnd, &c_bnd).unwrap();
+        assert_eq!(mgr.object_id(&b_bnd).unwrap(), mgr.object_id(&c_bnd).unwrap());
+        assert_eq!(mgr.object_id(&a_bnd).unwrap(), id);
+        let (mut b, _) = mgr.load(&b_bnd).unwrap();
+        b.t = JsType::JsNum(2.);
+        mgr.store(b, None).unwrap();
+        mgr.collect(CollectionKind::Full);
+        assert_eq!(mgr.object_id(&a_bnd).unwrap(), id);
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 2);
+
+        // The object outlives the variable it was allocated for
+        mgr.store_ref(&x_bnd, &a_bnd).unwrap();
+        let (mut a, _) = mgr.load(&a_bnd).unwrap();
+        a.t = JsType::JsNum(3.);
+        mgr.store(a, None).unwrap();
+        mgr.collect(CollectionKind::Full);
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 2);
+        assert_eq!(mgr.object_id(&a_bnd).unwrap(), None);
+        assert_eq!(mgr.object_id(&x_bnd).unwrap(), id);
+        match mgr.load(&x_bnd).unwrap() {
+            (_, Some(JsPtrEnum::JsObj(ref obj))) => assert_eq!(obj.dict.len(), 1),
+            _ => unreachable!(),
+        }
+        assert!(mgr.store_ref(&x_bnd, &Binding::new("nope".to_owned())).is_err());
+    }
+
+    #[test]
+    fn test_rebind_leaves_aliases_alone() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.push_scope(&Exp::Undefined);
+        let (a, a_ptr) = test_utils::make_str("a");
+        let a_bnd = mgr.alloc(a, Some(a_ptr)).unwrap();
+        let b_bnd = mgr.alloc_ref(test_utils::make_num(0.), &a_bnd).unwrap();
+        let id = mgr.object_id(&a_bnd).unwrap();
+
+        // Storing through b writes to the object it shares with a
+        let (b, _) = mgr.load(&b_bnd).unwrap();
+        let (_, b_ptr) = test_utils::make_str("b");
+        mgr.store(b.clone(), Some(b_ptr)).unwrap();
+        assert_eq!(mgr.object_id(&b_bnd).unwrap(), id);
+        match mgr.load(&a_bnd).unwrap() {
+            (_, Some(JsPtrEnum::JsStr(ref s))) => assert_eq!(s.text, "b"),
+            _ => unreachable!(),
+        }
+
+        // `b = "c"` gives b a new string, even though b already had one
+        let (_, c_ptr) = test_utils::make_str("c");
+        mgr.rebind(b, Some(c_ptr)).unwrap();
+        assert_eq!(mgr.object_id(&a_bnd).unwrap(), id);
+        assert!(mgr.object_id(&b_bnd).unwrap() != id);
+        match mgr.load(&a_bnd).unwrap() {
+            (_, Some(JsPtrEnum::JsStr(ref s))) => assert_eq!(s.text, "b"),
+            _ => unreachable!(),
+        }
+        match mgr.load(&b_bnd).unwrap() {
+            (_, Some(JsPtrEnum::JsStr(ref s))) => assert_eq!(s.text, "c"),
+            _ => unreachable!(),
+        }
+        mgr.collect(CollectionKind::Full);
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 2);
+    }
+
+    /// Describe everything a failed operation mustn't change: every scope's
+    /// variables, and the object each pointer variable refers to.
+    fn describe(mgr: &ScopeManager) -> Vec<String> {
+        let heap = mgr.alloc_box.lock().unwrap();
+        let mut lines = vec![format!("{} cells", heap.len())];
+        for (depth, scope) in mgr.scopes.iter().enumerate() {
+            let mut vars: Vec<_> = scope.vars()
+                                        .map(|var| {
+                                            let data = heap.find_id(&var.unique)
+                                                           .map(|alloc| {
+                                                               format!("{:?}", *alloc.borrow())
+                                                           });
+                                            format!("{}: {:?} -> {:?} {:?}",
+                                                    depth,
+                                                    var,
+                                                    heap.object_id(&var.unique),
+                                                    data)
+                                        })
+                                        .collect();
+            vars.sort();
+            lines.extend(vars);
+        }
+        lines
+    }
+
+    /// A scope holding a number, a string and an object with a string
+    /// property, in that order.
+    fn make_atomic_mgr() -> (ScopeManager, Vec<Binding>) {
+        let mut mgr = ScopeManager::new(alloc::make_alloc_box());
+        mgr.push_scope(&Exp::Undefined);
+        let n_bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        let (s, s_ptr) = test_utils::make_str("s");
+        let s_bnd = mgr.alloc(s, Some(s_ptr)).unwrap();
+        let (p, p_ptr) = test_utils::make_str("p");
+        let kvs = vec![(JsKey::JsSym("p".to_string()), p, Some(p_ptr))];
+        let (obj, obj_ptr) = test_utils::make_obj(kvs, mgr.get_alloc_box());
+        let obj_bnd = mgr.alloc(obj, Some(obj_ptr)).unwrap();
+        (mgr, vec![n_bnd, s_bnd, obj_bnd])
+    }
+
+    /// Run the `op`th of the operations `test_atomic_ops` checks.
+    fn run_atomic_op(op: usize, mgr: &mut ScopeManager, bnds: &[Binding]) -> Result<()> {
+        let make_obj = |nursery| {
+            let (p, p_ptr) = test_utils::make_str("new p");
+            let kvs = vec![(JsKey::JsSym("p".to_string()), p, Some(p_ptr))];
+            test_utils::make_obj(kvs, nursery)
+        };
+        match op {
+            0 => {
+                let (x, x_ptr) = test_utils::make_str("x");
+                mgr.alloc(x, Some(x_ptr)).map(|_| ())
+            }
+            1 => {
+                let (x, x_ptr) = make_obj(mgr.get_alloc_box());
+                mgr.alloc(x, Some(x_ptr)).map(|_| ())
+            }
+            2 => {
+                // The number becomes an object
+                let (mut n, _) = mgr.load(&bnds[0])?;
+                let (x, x_ptr) = make_obj(mgr.get_alloc_box());
+                n.t = x.t;
+                mgr.store(n, Some(x_ptr))
+            }
+            3 => {
+                // The string becomes an object
+                let (mut s, _) = mgr.load(&bnds[1])?;
+                let (x, x_ptr) = make_obj(mgr.get_alloc_box());
+                s.t = x.t;
+                mgr.store(s, Some(x_ptr))
+            }
+            4 => {
+                let (s, _) = mgr.load(&bnds[1])?;
+                let (_, s_ptr) = test_utils::make_str("changed");
+                mgr.store(s, Some(s_ptr))
+            }
+            5 => {
+                let (x, x_ptr) = test_utils::make_str("x");
+                mgr.set_property(&bnds[2], JsKey::JsSym("x".to_string()), x, Some(x_ptr))
+            }
+            6 => {
+                let (s, _) = mgr.load(&bnds[1])?;
+                mgr.set_property(&bnds[2], JsKey::JsSym("s".to_string()), s, None)
+            }
+            7 => mgr.alloc_ref(test_utils::make_num(0.), &bnds[2]).map(|_| ()),
+            _ => mgr.store_ref(&bnds[0], &bnds[2]),
+        }
+    }
+
+    #[test]
+    fn test_atomic_ops() {
+        for op in 0..9 {
+            // Fail each step that can fail in turn, until the operation gets
+            // through all of them
+            for step in 0.. {
+                let (mut mgr, bnds) = make_atomic_mgr();
+                let before = describe(&mgr);
+                mgr.alloc_box.lock().unwrap().fail_after(step);
+                if run_atomic_op(op, &mut mgr, &bnds).is_ok() {
+                    break;
+                }
+                assert_eq!(describe(&mgr), before, "op {} failed at step {}", op, step);
+                assert!(mgr.verify().is_empty());
+            }
+
+            // Running out of memory leaves nothing behind either
+            let (mut mgr, bnds) = make_atomic_mgr();
+            let before = describe(&mgr);
+            let limit = mgr.alloc_box.lock().unwrap().bytes();
+            mgr.set_heap_limit(Some(limit));
+            match run_atomic_op(op, &mut mgr, &bnds) {
+                Ok(()) => {}
+                // Backend methods report running out of memory as a failed
+                // pointer allocation
+                Err(GcError::PtrAlloc) => {
+                    assert_eq!(describe(&mgr), before, "op {} ran out of memory", op)
+                }
+                Err(e) => panic!("op {} failed: {:?}", op, e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_heap_limit_collects() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.push_scope(&Exp::Undefined);
+
+        // Room for one string
+        let (x, x_ptr) = test_utils::make_str("x");
+        mgr.set_heap_limit(Some(alloc::ptr_size(&x_ptr)));
+        let x_bnd = mgr.alloc(x, Some(x_ptr)).unwrap();
+        // Overwriting the string with a number releases its cell
+        let (mut x, _) = mgr.load(&x_bnd).unwrap();
+        x.t = JsType::JsNum(1.);
+        mgr.store(x, None).unwrap();
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 1);
+
+        // Going over the limit should collect the dead string to make room
+        let (y, y_ptr) = test_utils::make_str("y");
+        assert!(mgr.alloc(y, Some(y_ptr)).is_ok());
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_heap_limit_out_of_memory() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.push_scope(&Exp::Undefined);
+
+        let (x, x_ptr) = test_utils::make_str("x");
+        mgr.set_heap_limit(Some(alloc::ptr_size(&x_ptr)));
+        let x_bnd = mgr.alloc(x, Some(x_ptr)).unwrap();
+        // Redeclaring an allocated variable needs no room
+        let (x, _) = mgr.load(&x_bnd).unwrap();
+        let (_, other_ptr) = test_utils::make_str("other");
+        assert!(mgr.try_alloc(x, Some(other_ptr)).is_ok());
+        let (y, y_ptr) = test_utils::make_str("y");
+        let y_bnd = y.binding.clone();
+        let res = mgr.try_alloc(y.clone(), Some(y_ptr.clone()));
+        assert!(matches!(res, Err(HeapError::OutOfMemory)));
+        // Backend::alloc can only say that the pointer couldn't be allocated
+        let res = mgr.alloc(y, Some(y_ptr));
+        assert!(matches!(res, Err(GcError::PtrAlloc)));
+
+        // The failed allocation shouldn't leave anything behind
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 1);
+        assert_eq!(mgr.curr_scope().len(), 1);
+        assert!(mgr.load(&y_bnd).is_err());
+
+        // And the manager should still be usable
+        assert!(mgr.load(&x_bnd).is_ok());
+        let n_bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+
+        // A store that doesn't fit fails before anything is stored
+        let (mut n, _) = mgr.load(&n_bnd).unwrap();
+        let (s, s_ptr) = test_utils::make_str("s");
+        n.t = s.t;
+        assert!(matches!(mgr.try_store(n, Some(s_ptr)), Err(HeapError::OutOfMemory)));
+        assert!(matches!(mgr.load(&n_bnd), Ok((JsVar { t: JsType::JsNum(_), .. }, None))));
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 1);
+
+        mgr.set_heap_limit(None);
+        let (z, z_ptr) = test_utils::make_str("z");
+        assert!(mgr.alloc(z, Some(z_ptr)).is_ok());
+    }
+
+    #[test]
+    fn test_weak_ref_cleared() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.push_scope(&Exp::Undefined);
+
+        let (x, x_ptr) = test_utils::make_str("x");
+        let x_bnd = mgr.alloc(x, Some(x_ptr)).unwrap();
+        let weak = mgr.make_weak(&x_bnd).unwrap();
+        assert!(mgr.deref_weak(&weak).is_some());
+
+        // The weak reference alone doesn't keep the string alive
+        let (mut x, _) = mgr.load(&x_bnd).unwrap();
+        x.t = JsType::JsNum(1.);
+        mgr.store(x, None).unwrap();
+        mgr.push_scope(&Exp::Undefined);
+        mgr.pop_scope(None, GcYield::Forced).unwrap();
+        assert!(mgr.deref_weak(&weak).is_none());
+    }
+
+    #[test]
+    fn test_make_weak_primitive_fail() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let x_bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        assert!(mgr.make_weak(&x_bnd).is_err());
+    }
+
+    #[test]
+    fn test_weak_ref_follows_object() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.push_scope(&Exp::Undefined);
+        let (a, a_ptr) = test_utils::make_str("a");
+        let a_bnd = mgr.alloc(a, Some(a_ptr)).unwrap();
+        let b_bnd = mgr.alloc_ref(test_utils::make_num(0.), &a_bnd).unwrap();
+        let weak = mgr.make_weak(&a_bnd).unwrap();
+        assert_eq!(mgr.make_weak(&b_bnd).unwrap(), weak);
+
+        // An entry set through one reference is found through any other
+        let id = mgr.new_ephemeron_table();
+        mgr.ephemeron_set(id, &weak, test_utils::make_num(1.), None).unwrap();
+        let key = mgr.make_weak(&b_bnd).unwrap();
+        assert!(mgr.ephemeron_get(id, &key).is_some());
+
+        // `a = 1` leaves the object to b, and the weak reference with it
+        let (mut a, _) = mgr.load(&a_bnd).unwrap();
+        a.t = JsType::JsNum(1.);
+        mgr.store(a, None).unwrap();
+        mgr.collect(CollectionKind::Full);
+        assert!(mgr.deref_weak(&weak).is_some());
+        assert!(mgr.ephemeron_table(id).unwrap().has(&key));
+    }
+
+    #[test]
+    fn test_weak_ref_cleared_before_sweep() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.set_lazy_sweep(true);
+        mgr.push_scope(&Exp::Undefined);
+        let (x, x_ptr) = test_utils::make_str("x");
+        let x_bnd = mgr.alloc(x, Some(x_ptr)).unwrap();
+        let weak = mgr.make_weak(&x_bnd).unwrap();
+        let (mut x, _) = mgr.load(&x_bnd).unwrap();
+        x.t = JsType::JsNum(1.);
+        mgr.store(x, None).unwrap();
+
+        // The string is still in the heap, but the mark found it dead
+        mgr.push_scope(&Exp::Undefined);
+        mgr.pop_scope(None, GcYield::Forced).unwrap();
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 1);
+        assert!(mgr.deref_weak(&weak).is_none());
+    }
+
+    #[test]
+    fn test_ephemeron_lives_with_key() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.push_scope(&Exp::Undefined);
+
+        let (k, k_ptr) = test_utils::make_str("key");
+        let k_bnd = mgr.alloc(k, Some(k_ptr)).unwrap();
+        let key = mgr.make_weak(&k_bnd).unwrap();
+
+        let id = mgr.new_ephemeron_table();
+        let (v, v_ptr) = test_utils::make_str("value");
+        mgr.ephemeron_set(id, &key, v, Some(v_ptr)).unwrap();
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 2);
+
+        // The key is still reachable, so the value survives a collection
+        mgr.push_scope(&Exp::Undefined);
+        mgr.pop_scope(None, GcYield::Forced).unwrap();
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 2);
+        assert!(mgr.ephemeron_table(id).unwrap().has(&key));
+
+        // Once the key dies, the entry is cleared and the value goes with it
+        let (mut k, _) = mgr.load(&k_bnd).unwrap();
+        k.t = JsType::JsNum(1.);
+        mgr.store(k, None).unwrap();
+        mgr.push_scope(&Exp::Undefined);
+        mgr.pop_scope(None, GcYield::Forced).unwrap();
+        assert!(mgr.alloc_box.lock().unwrap().is_empty());
+        let table = mgr.ephemeron_table_mut(id).unwrap();
+        assert!(table.is_empty());
+        assert_eq!(table.take_cleared(), vec![key]);
+    }
+
+    #[test]
+    fn test_drop_ephemeron_table() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let (k, k_ptr) = test_utils::make_str("key");
+        let k_bnd = mgr.alloc(k, Some(k_ptr)).unwrap();
+        let key = mgr.make_weak(&k_bnd).unwrap();
+        let id = mgr.new_ephemeron_table();
+        let (v, v_ptr) = test_utils::make_str("value");
+        mgr.ephemeron_set(id, &key, v, Some(v_ptr)).unwrap();
+        assert!(mgr.drop_ephemeron_table(id));
+        assert!(mgr.ephemeron_table(id).is_none());
+        assert!(!mgr.drop_ephemeron_table(id));
+        mgr.collect(CollectionKind::Major);
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_ephemeron_delete() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let (k, k_ptr) = test_utils::make_str("key");
+        let k_bnd = mgr.alloc(k, Some(k_ptr)).unwrap();
+        let key = mgr.make_weak(&k_bnd).unwrap();
+        let id = mgr.new_ephemeron_table();
+        assert!(mgr.ephemeron_set(EphemeronId(id.0 + 1), &key, test_utils::make_num(1.), None)
+                   .is_err());
+
+        // The table holds the key itself through a reference of its own
+        let (k, _) = mgr.load(&k_bnd).unwrap();
+        mgr.ephemeron_set(id, &key, k, None).unwrap();
+        let held = mgr.ephemeron_table(id).unwrap().value_unique(&key).cloned().unwrap();
+        assert!(mgr.alloc_box.lock().unwrap().is_allocated(&held));
+
+        // Deleting the entry drops that reference, but not the object
+        assert!(mgr.ephemeron_delete(id, &key));
+        assert!(!mgr.ephemeron_delete(id, &key));
+        assert!(!mgr.alloc_box.lock().unwrap().is_allocated(&held));
+        mgr.collect(CollectionKind::Major);
+        assert!(mgr.deref_weak(&key).is_some());
+    }
+
+    #[test]
+    fn test_finalization_jobs() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.push_scope(&Exp::Undefined);
+
+        let (x, x_ptr) = test_utils::make_str("x");
+        let x_bnd = mgr.alloc(x, Some(x_ptr)).unwrap();
+        let weak = mgr.make_weak(&x_bnd).unwrap();
+        let (held, held_ptr) = test_utils::make_str("held");
+        mgr.register_finalizer(&weak, held, Some(held_ptr)).unwrap();
+
+        // Nothing to finalize while the target is alive
+        mgr.push_scope(&Exp::Undefined);
+        mgr.pop_scope(None, GcYield::Forced).unwrap();
+        assert!(mgr.take_finalization_jobs().is_empty());
+
+        let (mut x, _) = mgr.load(&x_bnd).unwrap();
+        x.t = JsType::JsNum(1.);
+        mgr.store(x, None).unwrap();
+        mgr.push_scope(&Exp::Undefined);
+        mgr.pop_scope(None, GcYield::Forced).unwrap();
+
+        // The held value is kept alive until its job has been taken
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 1);
+        let jobs = mgr.take_finalization_jobs();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].target, weak);
+        assert!(jobs[0].held_ptr.is_some());
+        mgr.collect(CollectionKind::Major);
+        assert!(mgr.alloc_box.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_finalization_jobs_lazy_sweep() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.set_lazy_sweep(true);
+        mgr.push_scope(&Exp::Undefined);
+        let (x, x_ptr) = test_utils::make_str("x");
+        let x_bnd = mgr.alloc(x, Some(x_ptr)).unwrap();
+        let weak = mgr.make_weak(&x_bnd).unwrap();
+        mgr.register_finalizer(&weak, test_utils::make_num(1.), None).unwrap();
+        let (mut x, _) = mgr.load(&x_bnd).unwrap();
+        x.t = JsType::JsNum(1.);
+        mgr.store(x, None).unwrap();
+
+        // The job is there as soon as the mark has found the target dead,
+        // whether or not the sweep has got to it yet
+        mgr.push_scope(&Exp::Undefined);
+        mgr.pop_scope(None, GcYield::Forced).unwrap();
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 1);
+        assert_eq!(mgr.take_finalization_jobs().len(), 1);
+        assert!(mgr.alloc_box.lock().unwrap().is_empty());
+        assert!(!mgr.unregister_finalizers(&weak));
+    }
+
+    #[test]
+    fn test_root_handle() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.push_scope(&Exp::Undefined);
+        let (x, x_ptr) = test_utils::make_str("x");
+        let x_bnd = mgr.alloc(x, Some(x_ptr)).unwrap();
+        let x_id = mgr.object_id(&x_bnd).unwrap();
+        let handle = mgr.root_handle(&x_bnd).unwrap();
+        let other = mgr.root_handle(&x_bnd).unwrap();
+
+        // The handle keeps the string once x holds a number instead
+        let (mut x, _) = mgr.load(&x_bnd).unwrap();
+        x.t = JsType::JsNum(1.);
+        mgr.store(x, None).unwrap();
+        mgr.collect(CollectionKind::Full);
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 1);
+        match mgr.load_handle(handle.id()) {
+            Some((_, Some(JsPtrEnum::JsStr(ref s)))) => assert_eq!(s.text, "x"),
+            _ => unreachable!(),
+        }
+        let y_bnd = mgr.alloc_handle_ref(test_utils::make_num(0.), handle.id()).unwrap();
+        assert_eq!(mgr.object_id(&y_bnd).unwrap(), x_id);
+        assert!(mgr.verify().is_empty());
 
+        // Dropping the handles leaves the string to y
+        let id = handle.id();
+        drop(handle);
+        drop(other);
+        assert!(mgr.load_handle(id).is_none());
+        assert!(mgr.alloc_handle_ref(test_utils::make_num(0.), id).is_err());
+        mgr.collect(CollectionKind::Full);
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 1);
+        let (mut y, _) = mgr.load(&y_bnd).unwrap();
+        y.t = JsType::JsNum(1.);
+        mgr.store(y, None).unwrap();
+        mgr.collect(CollectionKind::Full);
+        assert!(mgr.alloc_box.lock().unwrap().is_empty());
+    }
+
+    /// Holds objects the way an embedder's task queue might.
+    struct TaskQueue(Mutex<Vec<ObjectId>>);
+
+    impl RootProvider for TaskQueue {
+        fn roots(&self, roots: &mut Vec<ObjectId>) {
+            roots.extend(self.0.lock().unwrap().iter().cloned());
+        }
+    }
+
+    #[test]
+    fn test_root_provider() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
         mgr.push_scope(&Exp::Undefined);
-        match x.t {
-            JsType::JsNum(_) => x.t = JsType::JsNum(1.),
-            _ => unreachable!(),
-        };
-        assert!(mgr.store(x, None).is_ok())
+        let (p, p_ptr) = test_utils::make_str("p");
+        let kvs = vec![(JsKey::JsSym("p".to_string()), p, Some(p_ptr))];
+        let (obj, obj_ptr) = test_utils::make_obj(kvs, mgr.get_alloc_box());
+        let obj_bnd = mgr.alloc(obj, Some(obj_ptr)).unwrap();
+        let obj_id = mgr.object_id(&obj_bnd).unwrap().unwrap();
+
+        // Once the variable holds a number, only the queue holds on to the
+        // object, and through it the string
+        let queue = Arc::new(TaskQueue(Mutex::new(vec![obj_id])));
+        let id = mgr.add_root_provider(queue.clone());
+        let (mut obj, _) = mgr.load(&obj_bnd).unwrap();
+        obj.t = JsType::JsNum(1.);
+        mgr.store(obj, None).unwrap();
+        mgr.collect(CollectionKind::Full);
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 2);
+        assert!(mgr.alloc_box.lock().unwrap().is_live(obj_id));
+        assert!(mgr.start_background_mark());
+        mgr.finish_background_mark().unwrap();
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 2);
+        assert!(mgr.alloc_box.lock().unwrap().is_live(obj_id));
+
+        queue.0.lock().unwrap().clear();
+        mgr.collect(CollectionKind::Full);
+        assert!(mgr.alloc_box.lock().unwrap().is_empty());
+        assert!(mgr.remove_root_provider(id).is_some());
+        assert!(mgr.remove_root_provider(id).is_none());
     }
 
     #[test]
-    fn test_store_to_parent_scope_across_fn_boundary() {
-        let alloc_box = test_utils::make_alloc_box();
-        let mut mgr = ScopeManager::new(alloc_box);
+    fn test_host_objects() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.push_scope(&Exp::Undefined);
+        let (s, s_ptr) = test_utils::make_str("s");
+        let s_bnd = mgr.alloc(s, Some(s_ptr)).unwrap();
+        let s_id = mgr.object_id(&s_bnd).unwrap().unwrap();
+        let swept = Arc::new(AtomicUsize::new(0));
+        let host = SweepCounter {
+            objects: vec![s_id],
+            swept: swept.clone(),
+        };
+        let (obj, obj_ptr) = test_utils::make_obj(vec![], mgr.get_alloc_box());
+        let obj_bnd = mgr.alloc_host(obj, obj_ptr, Box::new(host)).unwrap();
+        assert_eq!(mgr.with_host(&s_bnd, |_| ()).unwrap(), None);
 
-        // Avoids having just the global scope available
-        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
-        let x = test_utils::make_num(1.);
-        let x_bnd = mgr.alloc(x, None).unwrap();
-        let copy = mgr.load(&x_bnd);
-        let (mut x, _) = copy.unwrap();
+        // Once s holds a number, only the host object refers to the string
+        let (mut s, _) = mgr.load(&s_bnd).unwrap();
+        s.t = JsType::JsNum(1.);
+        mgr.store(s, None).unwrap();
+        mgr.collect(CollectionKind::Full);
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 2);
+        assert!(mgr.start_background_mark());
+        mgr.finish_background_mark().unwrap();
+        match mgr.load_object(s_id) {
+            Some(JsPtrEnum::JsStr(ref s)) => assert_eq!(s.text, "s"),
+            _ => unreachable!(),
+        }
+        let refs = mgr.with_host(&obj_bnd, |host| {
+                          host.as_any().downcast_mut::<SweepCounter>().unwrap().objects.len()
+                      })
+                      .unwrap();
+        assert_eq!(refs, Some(1));
+        assert!(mgr.verify().is_empty());
 
-        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
-        match x.t {
-            JsType::JsNum(_) => x.t = JsType::JsNum(1.),
+        let (mut obj, _) = mgr.load(&obj_bnd).unwrap();
+        obj.t = JsType::JsNum(1.);
+        mgr.store(obj, None).unwrap();
+        mgr.collect(CollectionKind::Full);
+        assert!(mgr.alloc_box.lock().unwrap().is_empty());
+        assert_eq!(swept.load(Ordering::SeqCst), 1);
+        assert!(mgr.load_object(s_id).is_none());
+    }
+
+    #[test]
+    fn test_handle_scope() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.push_scope(&Exp::Undefined);
+        let mut bnds = Vec::new();
+        for text in &["a", "b"] {
+            let (var, ptr) = test_utils::make_str(text);
+            bnds.push(mgr.alloc(var, Some(ptr)).unwrap());
+        }
+        bnds.push(mgr.alloc(test_utils::make_num(1.), None).unwrap());
+
+        let mut scope = mgr.handle_scope();
+        let ids: Vec<_> = bnds.iter()
+                              .map(|bnd| mgr.scoped_handle(&mut scope, bnd).unwrap())
+                              .collect();
+        // Popping the scope that declared them would only move the strings
+        // to the parent scope, so make the variables let go of them instead
+        for bnd in &bnds {
+            let (mut var, _) = mgr.load(bnd).unwrap();
+            var.t = JsType::JsNum(2.);
+            mgr.store(var, None).unwrap();
+        }
+        mgr.collect(CollectionKind::Full);
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 2);
+        match mgr.load_handle(ids[2]) {
+            Some((JsVar { t: JsType::JsNum(n), .. }, None)) => assert!(f64::abs(n - 1.) < 0.0001),
             _ => unreachable!(),
-        };
-        assert!(mgr.store(x, None).is_err())
+        }
+
+        drop(scope);
+        mgr.collect(CollectionKind::Full);
+        assert!(mgr.alloc_box.lock().unwrap().is_empty());
+        assert!(ids.iter().all(|&id| mgr.load_handle(id).is_none()));
+        assert!(mgr.verify().is_empty());
     }
 
     #[test]
-    fn test_load_from_parent_scope_across_fn_boundary() {
-        let heap = test_utils::make_alloc_box();
+    fn test_compaction() {
+        let heap = alloc::make_alloc_box();
         let mut mgr = ScopeManager::new(heap);
+        mgr.set_compacting(true);
+        mgr.push_scope(&Exp::Undefined);
 
-        // Avoids having just the global scope available
-        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
         let (x, x_ptr) = test_utils::make_str("x");
         let x_bnd = mgr.alloc(x, Some(x_ptr)).unwrap();
+        let (y, y_ptr) = test_utils::make_str("y");
+        let y_bnd = mgr.alloc(y, Some(y_ptr)).unwrap();
+        let (var, ptr) = test_utils::make_str("test");
+        let key = JsKey::JsSym("key".to_string());
+        let (obj, obj_ptr) = test_utils::make_obj(vec![(key.clone(), var, Some(ptr))],
+                                                  mgr.get_alloc_box());
+        let obj_bnd = mgr.alloc(obj, Some(obj_ptr)).unwrap();
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 4);
 
-        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
-        let copy = mgr.load(&x_bnd);
+        // Kill `y`, then collect and compact
+        let (mut y, _) = mgr.load(&y_bnd).unwrap();
+        y.t = JsType::JsNum(1.);
+        mgr.store(y, None).unwrap();
+        mgr.collect(CollectionKind::Major);
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 3);
 
-        assert!(copy.is_err());
-        assert!(matches!(copy, Err(GcError::Load(_))));
+        // Everything that survived is still reachable through its binding
+        let (_, x_ptr) = mgr.load(&x_bnd).unwrap();
+        assert!(matches!(x_ptr, Some(JsPtrEnum::JsStr(_))));
+        let (obj, obj_ptr) = mgr.load(&obj_bnd).unwrap();
+        assert!(matches!(obj_ptr, Some(JsPtrEnum::JsObj(_))));
+
+        // The object's property wasn't a root before compaction and still isn't
+        let mut obj_ptr = obj_ptr;
+        if let Some(JsPtrEnum::JsObj(ref mut obj_struct)) = obj_ptr {
+            obj_struct.add_key(&obj.unique,
+                               key,
+                               test_utils::make_num(0.),
+                               None,
+                               &mut *(mgr.get_alloc_box().borrow_mut()));
+        }
+        mgr.store(obj, obj_ptr).unwrap();
+        mgr.collect(CollectionKind::Major);
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 2);
     }
 
     #[test]
-    fn test_load_from_parent_scope_no_fn_call() {
-        let heap = test_utils::make_alloc_box();
+    fn test_lazy_sweep() {
+        let heap = alloc::make_alloc_box();
         let mut mgr = ScopeManager::new(heap);
+        mgr.set_lazy_sweep(true);
+        mgr.push_scope(&Exp::Undefined);
 
-        // Avoids having just the global scope available
-        mgr.push_scope(&Exp::Call(box Exp::Undefined, vec![]));
         let (x, x_ptr) = test_utils::make_str("x");
         let x_bnd = mgr.alloc(x, Some(x_ptr)).unwrap();
+        let (mut x, _) = mgr.load(&x_bnd).unwrap();
+        x.t = JsType::JsNum(1.);
+        mgr.store(x, None).unwrap();
 
+        // Yielding only marks the heap, so the dead string is still there
         mgr.push_scope(&Exp::Undefined);
-        let copy = mgr.load(&x_bnd);
+        mgr.pop_scope(None, GcYield::Forced).unwrap();
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 1);
 
-        assert!(copy.is_ok());
-        let (var_copy, ptr_copy) = copy.unwrap();
-        assert!(matches!(var_copy, JsVar { t: JsType::JsPtr(_), .. }));
-        assert!(ptr_copy.is_some());
+        // It gets reclaimed when the next pointer needs room
+        let (y, y_ptr) = test_utils::make_str("y");
+        let y_bnd = mgr.alloc(y, Some(y_ptr)).unwrap();
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 1);
+        assert!(mgr.load(&y_bnd).is_ok());
     }
 
     #[test]
-    fn test_transfer_stack_with_yield() {
-        let heap = test_utils::make_alloc_box();
+    fn test_lazy_sweep_is_incremental() {
+        let heap = alloc::make_alloc_box();
         let mut mgr = ScopeManager::new(heap);
-        // Make some scopes
+        mgr.set_lazy_sweep(true);
         mgr.push_scope(&Exp::Undefined);
-        {
-            // Push a child scope
-            mgr.push_scope(&Exp::Undefined);
-            // Allocate some non-root variables (numbers)
-            mgr.alloc(test_utils::make_num(0.), None).unwrap();
-            mgr.alloc(test_utils::make_num(1.), None).unwrap();
-            mgr.alloc(test_utils::make_num(2.), None).unwrap();
+        let garbage = 2 * LAZY_SWEEP_BUDGET;
+        for _ in 0..garbage {
+            let (x, x_ptr) = test_utils::make_str("x");
+            let x_bnd = mgr.alloc(x, Some(x_ptr)).unwrap();
+            let (mut x, _) = mgr.load(&x_bnd).unwrap();
+            x.t = JsType::JsNum(1.);
+            mgr.store(x, None).unwrap();
+        }
+        mgr.push_scope(&Exp::Undefined);
+        mgr.pop_scope(None, GcYield::Forced).unwrap();
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), garbage);
 
-            // Make a string to put into an object
-            // (so it's heap-allocated and we can lose its ref from the object)
-            let (var, ptr) = test_utils::make_str("test");
+        // Each allocation only sweeps part of the heap, and the strings
+        // allocated in the meantime survive the sweep
+        let mut bnds = Vec::new();
+        while mgr.gc_phase() != GcPhase::Idle {
+            let (y, y_ptr) = test_utils::make_str("y");
+            bnds.push(mgr.alloc(y, Some(y_ptr)).unwrap());
+        }
+        assert_eq!(bnds.len(), 2);
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 2);
+        assert!(bnds.iter().all(|bnd| mgr.load(bnd).is_ok()));
+    }
 
-            // Create an obj of { true: 1.0, false: heap("test") }
-            let kvs = vec![(JsKey::JsSym("true".to_string()),
-                            test_utils::make_num(1.),
-                            None),
-                           (JsKey::JsSym("false".to_string()), var, Some(ptr))];
-            let (var, ptr) = test_utils::make_obj(kvs, mgr.alloc_box.clone());
+    #[test]
+    fn test_lazy_sweep_counts_freed() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let buf = events::SharedBuf(Arc::new(Mutex::new(Vec::new())));
+        mgr.set_event_sink(Some(Box::new(buf.clone())));
+        mgr.set_lazy_sweep(true);
+        mgr.push_scope(&Exp::Undefined);
+        for _ in 0..2 * LAZY_SWEEP_BUDGET {
+            let (x, x_ptr) = test_utils::make_str("x");
+            mgr.alloc(x, Some(x_ptr)).unwrap();
+        }
+        let (x, x_ptr) = test_utils::make_str("x");
+        let x_bnd = mgr.alloc(x, Some(x_ptr)).unwrap();
+        let (mut x, _) = mgr.load(&x_bnd).unwrap();
+        x.t = JsType::JsNum(1.);
+        mgr.store(x, None).unwrap();
+        mgr.push_scope(&Exp::Undefined);
+        mgr.pop_scope(None, GcYield::Forced).unwrap();
 
-            // Push the obj into the current scope
-            let bnd = mgr.alloc(var, Some(ptr)).unwrap();
-            // The heap should now have 2 things in it: an object and a string
-            assert_eq!(mgr.alloc_box.borrow().len(), 2);
+        // More is allocated while the heap is being swept than the sweep
+        // frees, so the heap ends the cycle bigger than it started it
+        while mgr.gc_phase() != GcPhase::Idle {
+            let (y, y_ptr) = test_utils::make_str("y");
+            mgr.alloc(y, Some(y_ptr)).unwrap();
+        }
+        mgr.set_event_sink(None);
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        assert!(out.contains("\"freed\":1,"));
+    }
 
-            // Replace the string in the object with something else so it's no longer live
-            let copy = mgr.load(&bnd);
-            let (var_cp, mut ptr_cp) = copy.unwrap();
-            let key = JsKey::JsSym("false".to_string());
-            match *&mut ptr_cp {
-                Some(JsPtrEnum::JsObj(ref mut obj)) => {
-                    obj.add_key(&var_cp.unique,
-                                key,
-                                test_utils::make_num(-1.),
-                                None,
-                                &mut *(mgr.alloc_box.borrow_mut()));
-                }
-                _ => unreachable!(),
-            }
-            mgr.store(var_cp, ptr_cp).unwrap();
-            // The heap should still have 2 things in it: an object and a string
-            assert_eq!(mgr.alloc_box.borrow().len(), 2);
+    #[test]
+    fn test_gc_prunes_every_scope() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.push_scope(&Exp::Undefined);
+        let (x, x_ptr) = test_utils::make_str("x");
+        let x_unique = x.unique.clone();
+        let x_bnd = mgr.alloc(x, Some(x_ptr)).unwrap();
+        mgr.push_scope(&Exp::Undefined);
+        mgr.push_scope(&Exp::Undefined);
 
-            // Kill the current scope & give its refs to the parent,
-            // allowing the GC to kick in beforehand.
-            mgr.pop_scope(None, true).unwrap();
-        }
-        // The object we created above should still exist
-        assert_eq!(mgr.curr_scope().len(), 1);
-        // But the string it had allocated shouldn't, since we leaked it into the void
-        assert_eq!(mgr.alloc_box.borrow().len(), 1);
+        // Pull the string out from under the outermost block scope
+        mgr.alloc_box.lock().unwrap().condemn(x_unique).unwrap();
+        mgr.pop_scope(None, GcYield::Forced).unwrap();
+        assert!(mgr.alloc_box.lock().unwrap().is_empty());
+        assert_eq!(mgr.scopes[1].len(), 0);
+        assert!(matches!(mgr.load(&x_bnd), Err(GcError::Load(_))));
+    }
+
+    #[test]
+    fn test_gc_prunes_closures() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.push_scope(&Exp::Undefined);
+        let (fn_var, fn_ptr) = test_utils::make_fn(&None, &Vec::new());
+        let fn_unique = fn_var.unique.clone();
+        mgr.alloc(fn_var, Some(fn_ptr)).unwrap();
+        let (x, x_ptr) = test_utils::make_str("x");
+        let x_unique = x.unique.clone();
+        mgr.alloc(x, Some(x_ptr)).unwrap();
+        mgr.pop_scope(Some(fn_unique.clone()), GcYield::Never).unwrap();
+        assert_eq!(mgr.closures[&fn_unique].len(), 2);
+
+        mgr.alloc_box.lock().unwrap().condemn(x_unique).unwrap();
+        mgr.push_scope(&Exp::Undefined);
+        mgr.pop_scope(None, GcYield::Forced).unwrap();
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 1);
+        assert_eq!(mgr.closures[&fn_unique].len(), 1);
+    }
+
+    #[test]
+    fn test_collect() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        assert_eq!(mgr.collect(CollectionKind::Minor), CollectionResult::default());
+
+        let (x, x_ptr) = test_utils::make_str("x");
+        let x_bnd = mgr.alloc(x, Some(x_ptr)).unwrap();
+        let (y, y_ptr) = test_utils::make_str("y");
+        mgr.alloc(y, Some(y_ptr)).unwrap();
+        let (mut x, _) = mgr.load(&x_bnd).unwrap();
+        x.t = JsType::JsNum(1.);
+        mgr.store(x, None).unwrap();
+
+        let res = mgr.collect(CollectionKind::Full);
+        assert_eq!(res, CollectionResult { freed: 1, live: 1 });
+        assert_eq!(mgr.collect(CollectionKind::Major), CollectionResult { freed: 0, live: 1 });
+    }
+
+    #[test]
+    fn test_minor_collect_keeps_ephemerons() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let (k, k_ptr) = test_utils::make_str("key");
+        let k_bnd = mgr.alloc(k, Some(k_ptr)).unwrap();
+        let key = mgr.make_weak(&k_bnd).unwrap();
+        let id = mgr.new_ephemeron_table();
+        mgr.ephemeron_set(id, &key, test_utils::make_num(1.), None).unwrap();
+        let (mut k, _) = mgr.load(&k_bnd).unwrap();
+        k.t = JsType::JsNum(1.);
+        mgr.store(k, None).unwrap();
+
+        mgr.collect(CollectionKind::Minor);
+        assert!(mgr.ephemeron_table(id).unwrap().has(&key));
+        mgr.collect(CollectionKind::Major);
+        assert!(!mgr.ephemeron_table(id).unwrap().has(&key));
+    }
+
+    #[test]
+    fn test_collect_until() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let (x, x_ptr) = test_utils::make_str("x");
+        let x_bnd = mgr.alloc(x, Some(x_ptr)).unwrap();
+        let (mut x, _) = mgr.load(&x_bnd).unwrap();
+        x.t = JsType::JsNum(1.);
+        mgr.store(x, None).unwrap();
+
+        // No time left, so no work gets done
+        assert!(!mgr.collect_until(Instant::now()));
+        assert_eq!(mgr.gc_phase(), GcPhase::Idle);
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 1);
+
+        let deadline = Instant::now() + Duration::from_secs(60);
+        assert!(mgr.collect_until(deadline));
+        assert_eq!(mgr.gc_phase(), GcPhase::Idle);
+        assert!(mgr.alloc_box.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_interleaved_gc_steps() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.gc_step(CollectionKind::Major);
+        assert_eq!(mgr.gc_phase(), GcPhase::Traced(CollectionKind::Major));
+        mgr.gc_step(CollectionKind::Major);
+        assert_eq!(mgr.gc_phase(), GcPhase::Marked(CollectionKind::Major));
+
+        // Allocating in the middle of a cycle sweeps part of the heap, which
+        // here is all of it. The new string was allocated after the mark, so
+        // it isn't mistaken for garbage
+        let (x, x_ptr) = test_utils::make_str("x");
+        let x_bnd = mgr.alloc(x, Some(x_ptr)).unwrap();
+        assert_eq!(mgr.gc_phase(), GcPhase::Idle);
+        assert!(mgr.load(&x_bnd).is_ok());
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_pop_scope_gc_policy() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.set_gc_policy(GcPolicy {
+            min_allocs: 2,
+            min_heap: 0,
+            growth_factor: 1.,
+            ..GcPolicy::new()
+        });
+        mgr.push_scope(&Exp::Undefined);
+        let (x, x_ptr) = test_utils::make_str("x");
+        let x_bnd = mgr.alloc(x, Some(x_ptr)).unwrap();
+        let (mut x, _) = mgr.load(&x_bnd).unwrap();
+        x.t = JsType::JsNum(1.);
+        mgr.store(x, None).unwrap();
+
+        // Only one allocation so far, so an allowed yield doesn't collect...
+        mgr.push_scope(&Exp::Undefined);
+        mgr.pop_scope(None, GcYield::Allowed).unwrap();
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 1);
+        // ...but a preferred one does
+        mgr.push_scope(&Exp::Undefined);
+        mgr.pop_scope(None, GcYield::Preferred).unwrap();
+        assert!(mgr.alloc_box.lock().unwrap().is_empty());
+        assert_eq!(mgr.gc_policy().allocs_since_gc(), 0);
+    }
+
+    #[test]
+    fn test_gc_stats() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.push_scope(&Exp::Undefined);
+        mgr.push_scope(&Exp::Undefined);
+        let (x, x_ptr) = test_utils::make_str("x");
+        let x_bnd = mgr.alloc(x, Some(x_ptr)).unwrap();
+        let (y, y_ptr) = test_utils::make_str("y");
+        mgr.alloc(y, Some(y_ptr)).unwrap();
+        let (mut x, _) = mgr.load(&x_bnd).unwrap();
+        x.t = JsType::JsNum(1.);
+        mgr.store(x, None).unwrap();
+        mgr.pop_scope(None, GcYield::Forced).unwrap();
+
+        let stats = mgr.gc_stats();
+        assert_eq!(stats.collections, 1);
+        assert!(stats.max_pause <= stats.total_pause);
+        assert_eq!(stats.objects_allocated, 2);
+        assert_eq!(stats.objects_freed, 1);
+        assert_eq!(stats.live_objects, 1);
+        assert!(stats.bytes_freed > 0);
+        assert_eq!(stats.live_bytes, stats.bytes_allocated - stats.bytes_freed);
+        assert_eq!(stats.max_scope_depth, 3);
+
+        // Values held outside any scope are counted too, but holding one
+        // that's already allocated allocates nothing
+        let (k, k_ptr) = test_utils::make_str("key");
+        let k_bnd = mgr.alloc(k, Some(k_ptr)).unwrap();
+        let weak = mgr.make_weak(&k_bnd).unwrap();
+        let (held, held_ptr) = test_utils::make_str("held");
+        mgr.register_finalizer(&weak, held, Some(held_ptr)).unwrap();
+        let (k, _) = mgr.load(&k_bnd).unwrap();
+        mgr.register_finalizer(&weak, k, None).unwrap();
+        let stats = mgr.gc_stats();
+        assert_eq!(stats.objects_allocated, 4);
+        assert_eq!(stats.live_objects, 3);
+    }
+
+    #[test]
+    fn test_event_log() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let buf = events::SharedBuf(Arc::new(Mutex::new(Vec::new())));
+        mgr.set_event_sink(Some(Box::new(buf.clone())));
+
+        mgr.push_scope(&Exp::Undefined);
+        let (x, x_ptr) = test_utils::make_str("x");
+        let x_bnd = mgr.alloc(x, Some(x_ptr)).unwrap();
+        // Stack-allocated variables don't touch the roots
+        let y_bnd = mgr.alloc(test_utils::make_num(1.), None).unwrap();
+        let (y, _) = mgr.load(&y_bnd).unwrap();
+        mgr.store(y, None).unwrap();
+        let (mut x, _) = mgr.load(&x_bnd).unwrap();
+        x.t = JsType::JsNum(1.);
+        mgr.store(x, None).unwrap();
+        mgr.pop_scope(None, GcYield::Forced).unwrap();
+        mgr.set_event_sink(None);
+
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let events: Vec<_> = out.lines()
+                                .map(|line| {
+                                    let start = line.find("\"event\":\"").unwrap() + 9;
+                                    let len = line[start..].find('"').unwrap();
+                                    line[start..start + len].to_owned()
+                                })
+                                .collect();
+        assert_eq!(events,
+                   vec!["scope_push",
+                        "alloc",
+                        "store",
+                        "scope_pop",
+                        "collection_start",
+                        "collection_end"]);
+        assert!(out.contains("\"freed\":1"));
+    }
+
+    #[test]
+    fn test_heap_snapshot() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.push_scope(&Exp::Undefined);
+        let (s, s_ptr) = test_utils::make_str("reachable");
+        let kvs = vec![(JsKey::JsSym("s".to_owned()), s, Some(s_ptr))];
+        let (obj, obj_ptr) = test_utils::make_obj(kvs, mgr.get_alloc_box());
+        mgr.alloc(obj, Some(obj_ptr)).unwrap();
+        let (garbage, garbage_ptr) = test_utils::make_str("garbage");
+        let garbage_bnd = mgr.alloc(garbage, Some(garbage_ptr)).unwrap();
+        let (mut garbage, _) = mgr.load(&garbage_bnd).unwrap();
+        garbage.t = JsType::JsNum(0.);
+        mgr.store(garbage, None).unwrap();
+
+        let mut out = Vec::new();
+        mgr.write_heap_snapshot(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        // The root, two scopes, the object and its string, and the closure,
+        // finalizer and ephemeron roots
+        assert!(out.contains("\"node_count\":8,"));
+        assert!(out.contains("\"reachable\""));
+        assert!(!out.contains("\"garbage\""));
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_verify() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.push_scope(&Exp::Undefined);
+        let (x, x_ptr) = test_utils::make_str("x");
+        let x_unique = x.unique.clone();
+        mgr.alloc(x, Some(x_ptr)).unwrap();
+        mgr.pop_scope(None, GcYield::Forced).unwrap();
+        assert!(mgr.verify().is_empty());
+
+        // Collect the string behind the manager's back
+        mgr.alloc_box.lock().unwrap().condemn(x_unique.clone()).unwrap();
+        mgr.alloc_box.lock().unwrap().mark_ptrs();
+        mgr.alloc_box.lock().unwrap().sweep_ptrs();
+        let violations = mgr.verify();
+        assert_eq!(violations.len(), 1);
+        assert!(matches!(violations[0], Violation::DanglingVar { ref unique, .. }
+                                                               if *unique == x_unique));
+    }
+
+    #[test]
+    fn test_verify_heap_mode() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.set_verify_heap(true);
+        let (x, x_ptr) = test_utils::make_str("x");
+        let x_unique = x.unique.clone();
+        mgr.alloc(x, Some(x_ptr)).unwrap();
+        mgr.push_scope(&Exp::Undefined);
+        assert!(mgr.take_verify_failures().is_empty());
+        mgr.alloc_box.lock().unwrap().condemn(x_unique).unwrap();
+        mgr.alloc_box.lock().unwrap().mark_ptrs();
+        mgr.alloc_box.lock().unwrap().sweep_ptrs();
+        mgr.push_scope(&Exp::Undefined);
+        mgr.alloc(test_utils::make_num(1.), None).unwrap();
+
+        // Each operation reports what it found, without failing
+        let failures = mgr.take_verify_failures();
+        assert_eq!(failures.len(), 2);
+        assert_eq!(failures[0].op, "push_scope");
+        assert_eq!(failures[1].op, "alloc");
+        assert!(failures[0].to_string().starts_with("heap verification failed after push_scope"));
+        assert!(mgr.take_verify_failures().is_empty());
+    }
+
+    #[test]
+    fn test_verify_held_values() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let (k, k_ptr) = test_utils::make_str("key");
+        let k_bnd = mgr.alloc(k, Some(k_ptr)).unwrap();
+        let key = mgr.make_weak(&k_bnd).unwrap();
+        let id = mgr.new_ephemeron_table();
+        let (v, v_ptr) = test_utils::make_str("value");
+        mgr.ephemeron_set(id, &key, v, Some(v_ptr)).unwrap();
+        let (held, held_ptr) = test_utils::make_str("held");
+        mgr.register_finalizer(&key, held, Some(held_ptr)).unwrap();
+        assert!(mgr.verify().is_empty());
+
+        // Drop both held values behind the manager's back
+        let value = mgr.ephemeron_table(id).unwrap().value_unique(&key).cloned().unwrap();
+        mgr.alloc_box.lock().unwrap().release(&value).unwrap();
+        let held = mgr.finalizers.roots().pop().unwrap();
+        mgr.alloc_box.lock().unwrap().release(&held).unwrap();
+        let violations = mgr.verify();
+        assert_eq!(violations.len(), 2);
+        assert!(violations.contains(&Violation::DanglingHeldValue {
+            location: format!("ephemeron table {}", id.0),
+            unique: value,
+        }));
+        assert!(violations.contains(&Violation::DanglingHeldValue {
+            location: "finalizers".to_owned(),
+            unique: held,
+        }));
+    }
+
+    #[test]
+    fn test_gc_stress() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.set_gc_stress(true);
+        mgr.push_scope(&Exp::Undefined);
+        let (x, x_ptr) = test_utils::make_str("x");
+        let x_bnd = mgr.alloc(x, Some(x_ptr)).unwrap();
+        assert_eq!(mgr.gc_stats().collections, 1);
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 1);
+
+        // The string dies as soon as nothing points to it
+        let (mut x, _) = mgr.load(&x_bnd).unwrap();
+        x.t = JsType::JsNum(1.);
+        mgr.store(x, None).unwrap();
+        assert_eq!(mgr.gc_stats().collections, 2);
+        assert!(mgr.alloc_box.lock().unwrap().is_empty());
+
+        mgr.pop_scope(None, GcYield::Never).unwrap();
+        assert_eq!(mgr.gc_stats().collections, 3);
+    }
+
+    #[test]
+    fn test_background_mark() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.push_scope(&Exp::Undefined);
+        let (x, x_ptr) = test_utils::make_str("x");
+        let x_bnd = mgr.alloc(x, Some(x_ptr)).unwrap();
+        let (garbage, garbage_ptr) = test_utils::make_str("garbage");
+        let garbage_bnd = mgr.alloc(garbage, Some(garbage_ptr)).unwrap();
+        let (mut garbage, _) = mgr.load(&garbage_bnd).unwrap();
+        garbage.t = JsType::JsNum(0.);
+        mgr.store(garbage, None).unwrap();
+        assert!(mgr.start_background_mark());
+        assert!(mgr.background_mark_running());
+        assert!(!mgr.start_background_mark());
+
+        // x dies and y is born while the mark is running
+        let (mut x, _) = mgr.load(&x_bnd).unwrap();
+        x.t = JsType::JsNum(1.);
+        mgr.store(x, None).unwrap();
+        let (y, y_ptr) = test_utils::make_str("y");
+        let y_unique = y.unique.clone();
+        mgr.alloc(y, Some(y_ptr)).unwrap();
+
+        let res = mgr.finish_background_mark().unwrap();
+        assert!(!mgr.background_mark_running());
+        assert!(mgr.alloc_box.lock().unwrap().is_allocated(&y_unique));
+        // x survives until the next collection if the mark got to it before
+        // it died
+        let late = mgr.collect(CollectionKind::Major).freed;
+        assert_eq!(res.freed + late, 2);
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 1);
+        assert!(mgr.finish_background_mark().is_none());
+    }
+
+    #[test]
+    fn test_cancel_background_mark() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        mgr.push_scope(&Exp::Undefined);
+        let (x, x_ptr) = test_utils::make_str("x");
+        mgr.alloc(x, Some(x_ptr)).unwrap();
+        assert!(!mgr.cancel_background_mark());
+
+        // Any other collection cancels the mark before it starts
+        assert!(mgr.start_background_mark());
+        mgr.collect(CollectionKind::Full);
+        assert!(!mgr.background_mark_running());
+        assert!(mgr.finish_background_mark().is_none());
+        assert!(mgr.start_background_mark());
+        mgr.pop_scope(None, GcYield::Forced).unwrap();
+        assert!(!mgr.background_mark_running());
+
+        assert!(mgr.start_background_mark());
+        assert!(mgr.cancel_background_mark());
+        assert!(!mgr.barrier.is_active());
+    }
+
+    #[test]
+    fn test_finalizer_write_barrier() {
+        let heap = alloc::make_alloc_box();
+        let mut mgr = ScopeManager::new(heap);
+        let (k, k_ptr) = test_utils::make_str("key");
+        let k_bnd = mgr.alloc(k, Some(k_ptr)).unwrap();
+        let key = mgr.make_weak(&k_bnd).unwrap();
+        assert!(mgr.start_background_mark());
+
+        // The held value is allocated while the mark is running
+        let (held, held_ptr) = test_utils::make_str("held");
+        mgr.register_finalizer(&key, held, Some(held_ptr)).unwrap();
+        let held = mgr.finalizers.roots().pop().unwrap();
+        let written = mgr.barrier.take();
+        assert!(written.contains(&held));
+        mgr.barrier.activate();
+        mgr.finish_background_mark().unwrap();
+        assert!(mgr.alloc_box.lock().unwrap().is_allocated(&held));
+    }
+
+    fn assert_send<T: Send>() {}
+
+    #[test]
+    fn test_send_scope_manager() {
+        assert_send::<SendScopeManager>();
+        let mut mgr = init_gc();
+        mgr.push_scope(&Exp::Undefined);
+
+        // Not while the interpreter still holds the nursery
+        let nursery = mgr.get_alloc_box();
+        let mut mgr = match mgr.into_send() {
+            Ok(_) => unreachable!(),
+            Err(mgr) => mgr,
+        };
+        drop(nursery);
+        let (p, p_ptr) = test_utils::make_str("p");
+        let kvs = vec![(JsKey::JsSym("p".to_string()), p, Some(p_ptr))];
+        let (obj, obj_ptr) = test_utils::make_obj(kvs, mgr.get_alloc_box());
+        let obj_bnd = mgr.alloc(obj, Some(obj_ptr)).unwrap();
+
+        // The whole runtime moves to another thread and back
+        let send = mgr.into_send().ok().unwrap();
+        let bnd = obj_bnd.clone();
+        let send = thread::spawn(move || {
+                       let mut send = send;
+                       let (s, s_ptr) = test_utils::make_str("s");
+                       send.try_alloc(s, Some(s_ptr)).unwrap();
+                       match send.load_var(&bnd).unwrap() {
+                           (_, Some(JsPtrEnum::JsObj(ref obj))) => assert_eq!(obj.dict.len(), 1),
+                           _ => unreachable!(),
+                       }
+                       send.collect(CollectionKind::Full);
+                       send
+                   })
+                       .join()
+                       .unwrap();
+        let mut mgr = send.into_local();
+        assert_eq!(mgr.alloc_box.lock().unwrap().len(), 3);
+        assert!(mgr.load(&obj_bnd).is_ok());
     }
 }
@@ -0,0 +1,20 @@
+use alloc::ObjectId;
+
+/// Identifies a `RootProvider` registered with a `ScopeManager`.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub struct RootProviderId(pub usize);
+
+/// Supplies roots from the embedder's own data structures, e.g. task queues
+/// or caches holding JS values, which the collector can't see otherwise.
+/// Providers are asked for their roots at the start of every mark, so what
+/// they report only needs to be right as of then. Providers hold on to
+/// objects rather than variables: an object stays alive for exactly as long
+/// as some provider keeps reporting it, or something else keeps it alive,
+/// whatever becomes of the variable it was taken from. Providers are shared
+/// through an `Arc`, so that a manager holding them can be made `Send`.
+pub trait RootProvider: Send + Sync {
+    /// Add every object the embedder is holding (see
+    /// `ScopeManager::object_id`) to `roots`. Objects that are no longer in
+    /// the heap are ignored.
+    fn roots(&self, roots: &mut Vec<ObjectId>);
+}
@@ -1,24 +1,30 @@
-use std::cell::RefCell;
-use std::collections::hash_map::{Entry, HashMap};
-use std::rc::Rc;
+use std::collections::hash_map::{Entry, HashMap, Values};
 use std::result;
+use std::sync::{Arc, Mutex};
 
-use jsrs_common::alloc_box::AllocBox;
 use jsrs_common::gc_error::{GcError, Result};
 use jsrs_common::types::js_var::{JsPtrEnum, JsType, JsVar};
 use jsrs_common::types::binding::{Binding, UniqueBinding};
 
+use alloc::{AllocBox, NurseryHolder};
+use concurrent::WriteBarrier;
+use verify::Violation;
+
 /// A logical scope in the AST. Represents any scoped block of Javascript code.
 /// parent: An optional parent scope, e.g. the caller of this function scope,
 ///         or the function that owns an `if` statement
-/// heap: A shared reference to the heap allocator.
+/// heap: A shared reference to the heap allocator. Pointers are allocated and
+///       stored with the nursery they're passed lent to it.
 /// stack: The stack of the current scope, containing all variables allocated
 ///        by this scope.
+/// barrier: Where to log the heap cells this scope writes, while a background
+///          mark is running.
 #[derive(Debug)]
 pub struct Scope {
-    heap: Rc<RefCell<AllocBox>>,
+    heap: Arc<Mutex<AllocBox>>,
     locals: HashMap<Binding, UniqueBinding>,
     stack: HashMap<UniqueBinding, JsVar>,
+    barrier: WriteBarrier,
     pub tag: ScopeTag,
 }
 
@@ -46,11 +52,20 @@ pub enum StoreError {
 
 impl Scope {
     /// Create a new, parentless scope node.
-    pub fn new(tag: ScopeTag, heap: &Rc<RefCell<AllocBox>>) -> Scope {
+    pub fn new(tag: ScopeTag, heap: &Arc<Mutex<AllocBox>>) -> Scope {
+        Scope::with_barrier(tag, heap, &WriteBarrier::new())
+    }
+
+    /// Create a new scope that logs its heap writes to a shared barrier.
+    pub fn with_barrier(tag: ScopeTag,
+                        heap: &Arc<Mutex<AllocBox>>,
+                        barrier: &WriteBarrier)
+                        -> Scope {
         Scope {
             heap: heap.clone(),
             locals: HashMap::new(),
             stack: HashMap::new(),
+            barrier: barrier.clone(),
             tag: tag,
         }
     }
@@ -61,28 +76,43 @@ impl Scope {
         self.stack.len()
     }
 
-    /// Push a new JsVar onto the stack, and maybe allocate a pointer in the heap.
-    pub fn push_var(&mut self, var: JsVar, ptr: Option<JsPtrEnum>) -> Result<()> {
+    /// Iterate over every variable on this scope's stack.
+    #[inline]
+    pub fn vars(&self) -> Values<UniqueBinding, JsVar> {
+        self.stack.values()
+    }
+
+    /// Push a new JsVar onto the stack, and maybe allocate a pointer in the
+    /// heap, promoting its properties out of `nursery`. If the allocation
+    /// fails, the variable isn't pushed either.
+    pub fn push_var(&mut self,
+                    var: JsVar,
+                    ptr: Option<JsPtrEnum>,
+                    nursery: &NurseryHolder)
+                    -> Result<()> {
         // Maybe insert the variable's pointer data into the heap
-        let res = match var.t {
+        match var.t {
             JsType::JsPtr(_) => {
                 if let Some(ptr) = ptr {
                     // Creating a new pointer creates a new root
-                    self.heap.borrow_mut().alloc(var.unique.clone(), ptr)
+                    let unique = var.unique.clone();
+                    self.heap
+                        .lock()
+                        .unwrap()
+                        .with_nursery(nursery, |heap| heap.alloc(unique, ptr))?;
+                    self.barrier.record(&var.unique);
                 } else {
                     return Err(GcError::PtrAlloc);
                 }
             }
             _ => {
                 if let Some(_) = ptr {
-                    Err(GcError::PtrAlloc)
-                } else {
-                    Ok(())
+                    return Err(GcError::PtrAlloc);
                 }
             }
-        };
+        }
         self.bind_var(var);
-        res
+        Ok(())
     }
 
     /// Push an already-allocated JsVar onto the stack.
@@ -102,23 +132,26 @@ impl Scope {
     pub fn get_var_copy(&self,
                         local: &Binding)
                         -> result::Result<(JsVar, Option<JsPtrEnum>), LookupError> {
-        if let Some(unique) = self.locals.get(local) {
-            if let Some(var) = self.stack.get(unique) {
-                match var.t {
-                    JsType::JsPtr(_) => {
-                        if let Some(alloc) = self.heap.borrow().find_id(unique) {
-                            Ok((var.clone(), Some(alloc.borrow().clone())))
-                        } else {
-                            // This case should be impossible unless you have an
-                            // invalid ptr, which should also be impossible.
-                            Err(LookupError::Unreachable)
-                        }
-                    }
-                    _ => Ok((var.clone(), None)),
+        let var = self.get_var(local)?;
+        match var.t {
+            JsType::JsPtr(_) => {
+                if let Some(alloc) = self.heap.lock().unwrap().find_id(&var.unique) {
+                    Ok((var.clone(), Some(alloc.borrow().clone())))
+                } else {
+                    // This case should be impossible unless you have an
+                    // invalid ptr, which should also be impossible.
+                    Err(LookupError::Unreachable)
                 }
-            } else {
-                Err(LookupError::Unreachable)
             }
+            _ => Ok((var.clone(), None)),
+        }
+    }
+
+    /// Return a reference to a variable, leaving any data it points to in
+    /// the heap.
+    pub fn get_var(&self, local: &Binding) -> result::Result<&JsVar, LookupError> {
+        if let Some(unique) = self.locals.get(local) {
+            self.stack.get(unique).ok_or(LookupError::Unreachable)
         } else if self.tag == ScopeTag::Call || matches!(self.tag, ScopeTag::Closure(_)) {
             // A nonexistent binding in the current scope might require searching
             // the scope tree upwards for the binding. However, if the current
@@ -131,11 +164,37 @@ impl Scope {
         }
     }
 
-    /// Try to update a variable that's been allocated.
+    /// Try to update a variable that's been allocated. The variable may
+    /// change type freely. Storing a pointer over one of the same type
+    /// overwrites the object it refers to, as every other reference to the
+    /// object will see. Storing one over a primitive or a pointer of another
+    /// type gives the variable a new object of its own instead. Properties
+    /// are promoted out of `nursery`.
     pub fn update_var(&mut self,
                       var: JsVar,
-                      ptr: Option<JsPtrEnum>)
+                      ptr: Option<JsPtrEnum>,
+                      nursery: &NurseryHolder)
                       -> result::Result<(), StoreError> {
+        self.store_var(var, ptr, true, nursery)
+    }
+
+    /// Like `update_var`, but a pointer always gets a new object of its own,
+    /// as in `b = "b"`. The object the variable referred to before is left
+    /// as it was, to its other references.
+    pub fn replace_var(&mut self,
+                       var: JsVar,
+                       ptr: Option<JsPtrEnum>,
+                       nursery: &NurseryHolder)
+                       -> result::Result<(), StoreError> {
+        self.store_var(var, ptr, false, nursery)
+    }
+
+    fn store_var(&mut self,
+                 var: JsVar,
+                 ptr: Option<JsPtrEnum>,
+                 in_place: bool,
+                 nursery: &NurseryHolder)
+                 -> result::Result<(), StoreError> {
         if !self.locals.contains_key(&var.binding) {
             if self.tag == ScopeTag::Call || matches!(self.tag, ScopeTag::Closure(_)) {
                 // Variable was not allocated.
@@ -144,19 +203,36 @@ impl Scope {
                 return Err(StoreError::CheckParent(var, ptr));
             }
         }
+        // Check that the variable is there before touching the heap, so that
+        // a bad store changes nothing
+        if !self.stack.contains_key(&var.unique) {
+            return Err(StoreError::BadStore);
+        }
         match var.t {
             JsType::JsPtr(ref tag) => {
-                if let Some(ref ptr) = ptr {
+                if let Some(ptr) = ptr {
                     // If the pointer and its underlying type are not equal, return an error.
                     if !tag.eq_ptr_type(&ptr) {
                         return Err(StoreError::PtrTypeMismatch);
                     }
-                    // A new root was potentially created
-                    // TODO FIXME? Cloning ptr is potentially expensive
-                    self.heap
-                             .borrow_mut()
-                             .update_ptr(&var.unique, ptr.clone())
-                             .map_err(|_| StoreError::BadStore)?;
+                    let mut heap = self.heap.lock().unwrap();
+                    let same_type = in_place &&
+                                    heap.find_id(&var.unique)
+                                        .map_or(false, |alloc| tag.eq_ptr_type(&*alloc.borrow()));
+                    let stored = heap.with_nursery(nursery, |heap| {
+                        if same_type {
+                            // A new root was potentially created
+                            heap.update_ptr(&var.unique, ptr)
+                        } else {
+                            // The object the variable referred to before, if
+                            // any, is left to its other references.
+                            heap.replace(var.unique.clone(), ptr)
+                        }
+                    });
+                    stored.map_err(|_| StoreError::BadStore)?;
+                    // The new data may point to cells a background mark
+                    // hasn't seen, so it has to be traced again.
+                    self.barrier.record(&var.unique);
                 } else {
                     return Err(StoreError::PtrTypeMismatch);
                 }
@@ -165,12 +241,14 @@ impl Scope {
                 if let Some(_) = ptr {
                     return Err(StoreError::PtrTypeMismatch);
                 }
-                // A root was potentially removed.
+                // A reference was potentially dropped. Other references may
+                // still share its object, so it's released rather than just
+                // condemned, lest a later store write through it.
                 // Blindly accept this Result, since we have no information
-                // about the type we're overwriting, and if we fail to condemn
+                // about the type we're overwriting, and if we fail to release
                 // a stack-allocated variable that's completely fine, since the
                 // heap doesn't store those anyway.
-                self.heap.borrow_mut().condemn(var.unique.clone()).ok();
+                self.heap.lock().unwrap().release(&var.unique).ok();
             }
         }
         // Update the variable on the stack
@@ -182,15 +260,13 @@ impl Scope {
         }
     }
 
-    pub fn trigger_gc(&mut self) {
-        // The interpreter says we can GC now
-        self.heap.borrow_mut().mark_ptrs();
-        self.heap.borrow_mut().sweep_ptrs();
-        // Pop any heap-allocated variables we just deleted
+    /// Called after the heap has been swept. Drop any heap-allocated
+    /// variables whose data was just collected.
+    pub fn prune(&mut self) {
         let uniques = self.stack.clone();
         for (unique, var) in uniques {
             if let JsType::JsPtr(_) = var.t {
-                if self.heap.borrow().find_id(&unique).is_none() {
+                if self.heap.lock().unwrap().find_id(&unique).is_none() {
                     self.stack.remove(&unique);
                 }
             }
@@ -203,9 +279,47 @@ impl Scope {
         }
     }
 
+    /// Check that every local binding points at a variable on the stack, and
+    /// that every pointer variable on the stack has a cell in the heap.
+    /// `location` describes this scope in any violations found.
+    pub fn verify(&self, location: &str, violations: &mut Vec<Violation>) {
+        for (local, unique) in &self.locals {
+            if !self.stack.contains_key(unique) {
+                violations.push(Violation::DanglingLocal {
+                    location: location.to_owned(),
+                    binding: local.clone(),
+                    unique: unique.clone(),
+                });
+            }
+        }
+        let heap = self.heap.lock().unwrap();
+        for (unique, var) in &self.stack {
+            if var.unique != *unique {
+                violations.push(Violation::MisfiledVar {
+                    location: location.to_owned(),
+                    key: unique.clone(),
+                    unique: var.unique.clone(),
+                });
+            }
+            if let JsType::JsPtr(_) = var.t {
+                if !heap.is_allocated(unique) {
+                    violations.push(Violation::DanglingVar {
+                        location: location.to_owned(),
+                        binding: var.binding.clone(),
+                        unique: unique.clone(),
+                    });
+                }
+            }
+        }
+    }
+
     /// Called when a scope exits. Transfers the stack of this scope to its parent,
     /// and returns the parent scope, which may be `None`.
     pub fn transfer_stack(&mut self, parent: &mut Scope, returning_closure: bool) -> Result<()> {
+        // Check every local first, so that nothing is moved if one is bad
+        if self.locals.values().any(|unique| !self.stack.contains_key(unique)) {
+            return Err(GcError::Scope);
+        }
         for (local, unique) in self.locals.drain() {
             let var = match self.stack.remove(&unique) {
                 Some(var) => var,
@@ -237,47 +351,55 @@ impl Scope {
 mod tests {
     use super::*;
 
+    use alloc::{self, OwnNursery};
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use jsrs_common::alloc_box::AllocBox as Nursery;
     use jsrs_common::gc_error::GcError;
     use jsrs_common::types::js_var::{JsVar, JsPtrEnum, JsKey, JsType};
     use jsrs_common::types::binding::Binding;
     use jsrs_common::test_utils;
     use jsrs_common::types::js_str::JsStrStruct;
 
+    use verify::Violation;
+
     #[test]
     fn test_push_var() {
-        let heap = test_utils::make_alloc_box();
+        let heap = alloc::make_alloc_box();
         let mut test_scope = Scope::new(ScopeTag::Block, &heap);
         let (var, ptr) = test_utils::make_str("test");
-        assert!(test_scope.push_var(var, Some(ptr)).is_ok());
-        assert_eq!(test_scope.heap.borrow().len(), 1);
+        assert!(test_scope.push_var(var, Some(ptr), &OwnNursery).is_ok());
+        assert_eq!(test_scope.heap.lock().unwrap().len(), 1);
         let var = test_utils::make_num(1.);
-        assert!(test_scope.push_var(var, None).is_ok());
-        assert_eq!(test_scope.heap.borrow().len(), 1);
+        assert!(test_scope.push_var(var, None, &OwnNursery).is_ok());
+        assert_eq!(test_scope.heap.lock().unwrap().len(), 1);
     }
 
     #[test]
     fn test_push_var_fail() {
-        let heap = test_utils::make_alloc_box();
+        let heap = alloc::make_alloc_box();
         let mut test_scope = Scope::new(ScopeTag::Block, &heap);
         let (var, ptr) = test_utils::make_str("test");
-        let res = test_scope.push_var(var, None);
+        let res = test_scope.push_var(var, None, &OwnNursery);
         assert!(res.is_err());
         assert!(matches!(res, Err(GcError::PtrAlloc)));
-        assert!(test_scope.heap.borrow().is_empty());
+        assert!(test_scope.heap.lock().unwrap().is_empty());
         let var = test_utils::make_num(1.);
-        let res = test_scope.push_var(var, Some(ptr));
+        let res = test_scope.push_var(var, Some(ptr), &OwnNursery);
         assert!(res.is_err());
         assert!(matches!(res, Err(GcError::PtrAlloc)));
-        assert!(test_scope.heap.borrow().is_empty());
+        assert!(test_scope.heap.lock().unwrap().is_empty());
     }
 
     #[test]
     fn test_get_var_copy() {
-        let heap = test_utils::make_alloc_box();
+        let heap = alloc::make_alloc_box();
         let mut test_scope = Scope::new(ScopeTag::Block, &heap);
         let (x, x_ptr) = test_utils::make_str("x");
         let x_bnd = x.binding.clone();
-        test_scope.push_var(x, Some(x_ptr)).unwrap();
+        test_scope.push_var(x, Some(x_ptr), &OwnNursery).unwrap();
 
         let copy = test_scope.get_var_copy(&x_bnd);
         assert!(copy.is_ok());
@@ -288,7 +410,7 @@ mod tests {
 
     #[test]
     fn test_get_var_copy_fail() {
-        let heap = test_utils::make_alloc_box();
+        let heap = alloc::make_alloc_box();
         let test_scope = Scope::new(ScopeTag::Block, &heap);
         let copy = test_scope.get_var_copy(&Binding::new("".to_string()));
         assert!(copy.is_err());
@@ -296,14 +418,14 @@ mod tests {
 
     #[test]
     fn test_update_var() {
-        let heap = test_utils::make_alloc_box();
+        let heap = alloc::make_alloc_box();
         let mut test_scope = Scope::new(ScopeTag::Block, &heap);
         let (x, x_ptr) = test_utils::make_str("x");
         let x_bnd = x.binding.clone();
-        assert!(test_scope.push_var(x, Some(x_ptr)).is_ok());
+        assert!(test_scope.push_var(x, Some(x_ptr), &OwnNursery).is_ok());
         let (update, _) = test_scope.get_var_copy(&x_bnd).unwrap();
         let update_ptr = Some(JsPtrEnum::JsStr(JsStrStruct::new("test")));
-        assert!(test_scope.update_var(update, update_ptr).is_ok());
+        assert!(test_scope.update_var(update, update_ptr, &OwnNursery).is_ok());
 
         let (update, update_ptr) = test_scope.get_var_copy(&x_bnd).unwrap();
         match update_ptr.unwrap() {
@@ -313,38 +435,60 @@ mod tests {
         assert_eq!(update.unique, *test_scope.locals.get(&x_bnd).unwrap());
     }
 
+    #[test]
+    fn test_replace_var() {
+        let heap = alloc::make_alloc_box();
+        let mut test_scope = Scope::new(ScopeTag::Block, &heap);
+        let (x, x_ptr) = test_utils::make_str("x");
+        let x_bnd = x.binding.clone();
+        assert!(test_scope.push_var(x, Some(x_ptr), &OwnNursery).is_ok());
+        let (update, _) = test_scope.get_var_copy(&x_bnd).unwrap();
+        let id = heap.lock().unwrap().object_id(&update.unique);
+        let update_ptr = Some(JsPtrEnum::JsStr(JsStrStruct::new("test")));
+        assert!(test_scope.replace_var(update.clone(), update_ptr, &OwnNursery).is_ok());
+
+        // The variable has a new object, and the old one is left as it was
+        assert_eq!(heap.lock().unwrap().len(), 2);
+        assert!(heap.lock().unwrap().object_id(&update.unique) != id);
+        match test_scope.get_var_copy(&x_bnd).unwrap().1 {
+            Some(JsPtrEnum::JsStr(JsStrStruct{text: ref s})) => assert_eq!(s, "test"),
+            _ => unreachable!(),
+        }
+    }
+
     #[test]
     fn test_update_var_fail() {
-        let heap = test_utils::make_alloc_box();
+        let heap = alloc::make_alloc_box();
         let mut test_scope = Scope::new(ScopeTag::Block, &heap);
         let (x, x_ptr) = test_utils::make_str("x");
         let x_bnd = x.binding.clone();
-        assert!(test_scope.push_var(x, Some(x_ptr)).is_ok());
+        assert!(test_scope.push_var(x, Some(x_ptr), &OwnNursery).is_ok());
         let (mut update, update_ptr) = test_scope.get_var_copy(&x_bnd).unwrap();
-        let res = test_scope.update_var(update.clone(), None);
+        let res = test_scope.update_var(update.clone(), None, &OwnNursery);
         assert!(res.is_err());
         assert!(matches!(res, Err(StoreError::PtrTypeMismatch)));
 
         update.t = JsType::JsNum(1.);
-        let res = test_scope.update_var(update, update_ptr);
+        let res = test_scope.update_var(update, update_ptr, &OwnNursery);
         assert!(res.is_err());
         assert!(matches!(res, Err(StoreError::PtrTypeMismatch)));
     }
 
     #[test]
     fn test_transfer_stack_no_closure() {
-        let heap = test_utils::make_alloc_box();
+        let heap = alloc::make_alloc_box();
         let mut parent_scope = Scope::new(ScopeTag::Block, &heap);
         {
             let mut test_scope = Scope::new(ScopeTag::Block, &heap);
-            test_scope.push_var(test_utils::make_num(0.), None).unwrap();
-            test_scope.push_var(test_utils::make_num(1.), None).unwrap();
-            test_scope.push_var(test_utils::make_num(2.), None).unwrap();
+            test_scope.push_var(test_utils::make_num(0.), None, &OwnNursery).unwrap();
+            test_scope.push_var(test_utils::make_num(1.), None, &OwnNursery).unwrap();
+            test_scope.push_var(test_utils::make_num(2.), None, &OwnNursery).unwrap();
             let kvs = vec![(JsKey::JsSym("true".to_string()),
                             test_utils::make_num(1.),
                             None)];
-            let (var, ptr) = test_utils::make_obj(kvs, heap.clone());
-            test_scope.push_var(var, Some(ptr)).unwrap();
+            let nursery = Rc::new(RefCell::new(Nursery::new()));
+            let (var, ptr) = test_utils::make_obj(kvs, nursery.clone());
+            test_scope.push_var(var, Some(ptr), &nursery).unwrap();
             test_scope.transfer_stack(&mut parent_scope, false).unwrap();
         }
         assert_eq!(parent_scope.stack.len(), 1);
@@ -352,7 +496,7 @@ mod tests {
 
     #[test]
     fn test_transfer_stack_return_closure() {
-        let heap = test_utils::make_alloc_box();
+        let heap = alloc::make_alloc_box();
         let mut closure_scope = Scope::new(ScopeTag::Block, &heap);
         let fn_unique = {
             // Create a child scope
@@ -363,14 +507,14 @@ mod tests {
             let fn_unique = var.unique.clone();
 
             // Alocate the function
-            test_scope.push_var(var, Some(test_fn)).unwrap();
+            test_scope.push_var(var, Some(test_fn), &OwnNursery).unwrap();
 
             // Create and allocate a number
-            test_scope.push_var(test_utils::make_num(1.), None).unwrap();
+            test_scope.push_var(test_utils::make_num(1.), None, &OwnNursery).unwrap();
 
             // Create and allocate a string
             let (var, ptr) = test_utils::make_str("test");
-            test_scope.push_var(var, Some(ptr)).unwrap();
+            test_scope.push_var(var, Some(ptr), &OwnNursery).unwrap();
 
             // Kill the current scope, signalling that we're returning a closure
             test_scope.transfer_stack(&mut closure_scope, true).unwrap();
@@ -379,8 +523,37 @@ mod tests {
         // The closure scope should contain the entire environment of the old scope
         assert_eq!(closure_scope.stack.len(), 3);
         // The heap should contain a string and a function
-        assert_eq!(heap.borrow().len(), 2);
+        assert_eq!(heap.lock().unwrap().len(), 2);
         // The function should still be allocated
-        assert!(heap.borrow().find_id(&fn_unique).is_some());
+        assert!(heap.lock().unwrap().find_id(&fn_unique).is_some());
+    }
+
+    #[test]
+    fn test_verify() {
+        let heap = alloc::make_alloc_box();
+        let mut test_scope = Scope::new(ScopeTag::Block, &heap);
+        let (x, x_ptr) = test_utils::make_str("x");
+        let x_unique = x.unique.clone();
+        test_scope.push_var(x, Some(x_ptr), &OwnNursery).unwrap();
+        test_scope.push_var(test_utils::make_num(1.), None, &OwnNursery).unwrap();
+        let mut violations = Vec::new();
+        test_scope.verify("test", &mut violations);
+        assert!(violations.is_empty());
+
+        let y = test_utils::make_num(2.);
+        test_scope.locals.insert(y.binding.clone(), y.unique.clone());
+        heap.lock().unwrap().condemn(x_unique.clone()).unwrap();
+        heap.lock().unwrap().mark_ptrs();
+        heap.lock().unwrap().sweep_ptrs();
+        test_scope.verify("test", &mut violations);
+        assert_eq!(violations.len(), 2);
+        assert!(violations.contains(&Violation::DanglingLocal {
+            location: "test".to_owned(),
+            binding: y.binding,
+            unique: y.unique,
+        }));
+        assert!(violations.iter().any(|v| {
+            matches!(*v, Violation::DanglingVar { ref unique, .. } if *unique == x_unique)
+        }));
     }
 }
@@ -0,0 +1,337 @@
+use std::collections::hash_map::HashMap;
+use std::io::{self, Write};
+
+use jsrs_common::types::js_var::{JsKey, JsPtrEnum, JsType};
+use jsrs_common::types::binding::UniqueBinding;
+
+use alloc::{AllocBox, ObjectId, ptr_size};
+use events::json_str;
+
+/// The fields of each node, in the order they're written out.
+const NODE_FIELDS: usize = 6;
+
+/// Node types, as indices into the `node_types` list in the snapshot's
+/// metadata.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum NodeType {
+    String = 2,
+    Object = 3,
+    Closure = 5,
+    Native = 8,
+    Synthetic = 9,
+}
+
+/// Edge types, as indices into the `edge_types` list in the snapshot's
+/// metadata.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EdgeType {
+    Context = 0,
+    Element = 1,
+    Property = 2,
+    Internal = 3,
+}
+
+#[derive(Debug)]
+struct Node {
+    kind: NodeType,
+    name: usize,
+    self_size: usize,
+    edges: Vec<Edge>,
+}
+
+/// name_or_index: An index into the string table for named edges, or the
+///                element's index for `Element` edges.
+#[derive(Debug)]
+struct Edge {
+    kind: EdgeType,
+    name_or_index: usize,
+    to: usize,
+}
+
+/// Builds a heap snapshot in the JSON format V8 uses, which the Memory panel
+/// of Chrome DevTools can load. Node 0 is the synthetic root that DevTools
+/// computes retention from; everything else hangs off it.
+pub struct HeapSnapshot<'a> {
+    heap: &'a AllocBox,
+    nodes: Vec<Node>,
+    strings: Vec<String>,
+    string_ids: HashMap<String, usize>,
+    cells: HashMap<ObjectId, usize>,
+}
+
+impl<'a> HeapSnapshot<'a> {
+    pub fn new(heap: &'a AllocBox) -> HeapSnapshot<'a> {
+        let mut snapshot = HeapSnapshot {
+            heap: heap,
+            nodes: Vec::new(),
+            strings: Vec::new(),
+            string_ids: HashMap::new(),
+            cells: HashMap::new(),
+        };
+        snapshot.add_node(NodeType::Synthetic, "", 0);
+        snapshot
+    }
+
+    #[inline]
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Add a synthetic node, such as a scope, that isn't a heap cell itself.
+    pub fn add_synthetic(&mut self, name: &str) -> usize {
+        self.add_node(NodeType::Synthetic, name, 0)
+    }
+
+    /// Add a synthetic node that the root points to directly.
+    pub fn add_root(&mut self, name: &str) -> usize {
+        let index = self.add_synthetic(name);
+        self.add_element(0, index);
+        index
+    }
+
+    pub fn add_edge(&mut self, from: usize, kind: EdgeType, name: &str, to: usize) {
+        let name = self.string_id(name);
+        self.nodes[from].edges.push(Edge {
+            kind: kind,
+            name_or_index: name,
+            to: to,
+        });
+    }
+
+    /// Add an unnamed edge, numbered after the node's existing edges.
+    pub fn add_element(&mut self, from: usize, to: usize) {
+        let index = self.nodes[from].edges.len();
+        self.nodes[from].edges.push(Edge {
+            kind: EdgeType::Element,
+            name_or_index: index,
+            to: to,
+        });
+    }
+
+    /// Return the node for the heap cell a reference resolves to, adding it
+    /// and everything reachable from it if it isn't in the snapshot yet.
+    /// References to the same object share its node. Returns `None` if the
+    /// reference doesn't resolve to anything.
+    pub fn cell(&mut self, unique: &UniqueBinding) -> Option<usize> {
+        match self.heap.object_id(unique) {
+            Some(id) => self.object(id),
+            None => None,
+        }
+    }
+
+    /// Return the node for an object, adding it and everything reachable
+    /// from it if it isn't in the snapshot yet. Returns `None` if the object
+    /// isn't in the heap.
+    pub fn object(&mut self, id: ObjectId) -> Option<usize> {
+        let heap = self.heap;
+        let mut grey = Vec::new();
+        let index = self.object_node(id, &mut grey);
+        while let Some((from, id)) = grey.pop() {
+            let mut props = Vec::new();
+            if let Some(alloc) = heap.object(id) {
+                if let JsPtrEnum::JsObj(ref obj) = *alloc.borrow() {
+                    for (key, var) in &obj.dict {
+                        if let JsType::JsPtr(_) = var.t {
+                            props.push((key_name(key), var.unique.clone()));
+                        }
+                    }
+                }
+            }
+            for (name, child) in props {
+                if let Some(to) = self.cell_node(&child, &mut grey) {
+                    self.add_edge(from, EdgeType::Property, &name, to);
+                }
+            }
+            let mut host_objects = Vec::new();
+            if let Some(host) = heap.object_host(id) {
+                host.borrow().trace(&mut host_objects);
+            }
+            for child in host_objects {
+                if let Some(to) = self.object_node(child, &mut grey) {
+                    self.add_edge(from, EdgeType::Internal, "(host)", to);
+                }
+            }
+        }
+        index
+    }
+
+    fn cell_node(&mut self,
+                 unique: &UniqueBinding,
+                 grey: &mut Vec<(usize, ObjectId)>)
+                 -> Option<usize> {
+        match self.heap.object_id(unique) {
+            Some(id) => self.object_node(id, grey),
+            None => None,
+        }
+    }
+
+    fn object_node(&mut self, id: ObjectId, grey: &mut Vec<(usize, ObjectId)>) -> Option<usize> {
+        if let Some(&index) = self.cells.get(&id) {
+            return Some(index);
+        }
+        let (kind, name, size) = match self.heap.object(id) {
+            Some(alloc) => {
+                let ptr = alloc.borrow();
+                let (kind, name) = match *ptr {
+                    JsPtrEnum::JsStr(ref s) => (NodeType::String, s.text.clone()),
+                    JsPtrEnum::JsObj(_) => (NodeType::Object, "Object".to_owned()),
+                    JsPtrEnum::JsFn(_) => (NodeType::Closure, "Function".to_owned()),
+                    _ => (NodeType::Native, "(native)".to_owned()),
+                };
+                let size = ptr_size(&*ptr);
+                (kind, name, size)
+            }
+            None => return None,
+        };
+        let index = self.add_node(kind, &name, size);
+        self.cells.insert(id, index);
+        grey.push((index, id));
+        Some(index)
+    }
+
+    fn add_node(&mut self, kind: NodeType, name: &str, self_size: usize) -> usize {
+        let name = self.string_id(name);
+        self.nodes.push(Node {
+            kind: kind,
+            name: name,
+            self_size: self_size,
+            edges: Vec::new(),
+        });
+        self.nodes.len() - 1
+    }
+
+    fn string_id(&mut self, s: &str) -> usize {
+        if let Some(&id) = self.string_ids.get(s) {
+            return id;
+        }
+        let id = self.strings.len();
+        self.strings.push(s.to_owned());
+        self.string_ids.insert(s.to_owned(), id);
+        id
+    }
+
+    pub fn write(&self, out: &mut Write) -> io::Result<()> {
+        let edge_count = self.nodes.iter().fold(0, |n, node| n + node.edges.len());
+        write!(out,
+               "{{\"snapshot\":{{\"meta\":{{\
+                \"node_fields\":[\"type\",\"name\",\"id\",\"self_size\",\"edge_count\",\
+                \"trace_node_id\"],\
+                \"node_types\":[[\"hidden\",\"array\",\"string\",\"object\",\"code\",\
+                \"closure\",\"regexp\",\"number\",\"native\",\"synthetic\",\
+                \"concatenated string\",\"sliced string\"],\
+                \"string\",\"number\",\"number\",\"number\",\"number\"],\
+                \"edge_fields\":[\"type\",\"name_or_index\",\"to_node\"],\
+                \"edge_types\":[[\"context\",\"element\",\"property\",\"internal\",\
+                \"hidden\",\"shortcut\",\"weak\"],\"string_or_number\",\"node\"]}},\
+                \"node_count\":{},\"edge_count\":{},\"trace_function_count\":0}},\n",
+               self.nodes.len(),
+               edge_count)?;
+
+        write!(out, "\"nodes\":[")?;
+        for (i, node) in self.nodes.iter().enumerate() {
+            if i > 0 {
+                writeln!(out, ",")?;
+            }
+            // V8 gives heap objects odd ids, so we do too.
+            write!(out,
+                   "{},{},{},{},{},0",
+                   node.kind as usize,
+                   node.name,
+                   2 * i + 1,
+                   node.self_size,
+                   node.edges.len())?;
+        }
+        write!(out, "],\n\"edges\":[")?;
+        let mut first = true;
+        for edge in self.nodes.iter().flat_map(|node| node.edges.iter()) {
+            if !first {
+                writeln!(out, ",")?;
+            }
+            first = false;
+            write!(out,
+                   "{},{},{}",
+                   edge.kind as usize,
+                   edge.name_or_index,
+                   edge.to * NODE_FIELDS)?;
+        }
+        write!(out,
+               "],\n\"trace_function_infos\":[],\"trace_tree\":[],\"samples\":[],\
+                \"locations\":[],\n\"strings\":[")?;
+        for (i, s) in self.strings.iter().enumerate() {
+            if i > 0 {
+                writeln!(out, ",")?;
+            }
+            write!(out, "{}", json_str(s))?;
+        }
+        writeln!(out, "]}}")
+    }
+}
+
+fn key_name(key: &JsKey) -> String {
+    match *key {
+        JsKey::JsSym(ref s) => s.clone(),
+        _ => format!("{:?}", key),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use jsrs_common::alloc_box::AllocBox as Nursery;
+    use jsrs_common::test_utils;
+    use jsrs_common::types::js_var::JsKey;
+
+    #[test]
+    fn test_cell_edges() {
+        let heap = alloc::make_alloc_box();
+        let (s, s_ptr) = test_utils::make_str("test");
+        let kvs = vec![(JsKey::JsSym("s".to_owned()), s, Some(s_ptr)),
+                       (JsKey::JsSym("n".to_owned()), test_utils::make_num(1.), None)];
+        let nursery = Rc::new(RefCell::new(Nursery::new()));
+        let (obj, obj_ptr) = test_utils::make_obj(kvs, nursery.clone());
+        heap.lock()
+            .unwrap()
+            .with_nursery(&nursery, |heap| heap.alloc(obj.unique.clone(), obj_ptr))
+            .unwrap();
+
+        let heap = heap.lock().unwrap();
+        let mut snapshot = HeapSnapshot::new(&*heap);
+        let root = snapshot.add_root("(scope)");
+        let obj_node = snapshot.cell(&obj.unique).unwrap();
+        snapshot.add_edge(root, EdgeType::Context, "obj", obj_node);
+        // The object and the string it points to, but not the number
+        assert_eq!(snapshot.node_count(), 4);
+        assert_eq!(snapshot.cell(&obj.unique), Some(obj_node));
+        assert_eq!(snapshot.nodes[obj_node].kind, NodeType::Object);
+        assert_eq!(snapshot.nodes[obj_node].edges.len(), 1);
+        let to = snapshot.nodes[obj_node].edges[0].to;
+        assert_eq!(snapshot.nodes[to].kind, NodeType::String);
+        assert_eq!(snapshot.strings[snapshot.nodes[to].name], "test");
+    }
+
+    #[test]
+    fn test_write() {
+        let heap = alloc::make_alloc_box();
+        let heap = heap.lock().unwrap();
+        let mut snapshot = HeapSnapshot::new(&*heap);
+        let a = snapshot.add_root("a");
+        let b = snapshot.add_synthetic("b\"");
+        snapshot.add_edge(a, EdgeType::Internal, "b", b);
+
+        let mut out = Vec::new();
+        snapshot.write(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("\"node_count\":3,\"edge_count\":2,"));
+        assert!(out.contains("\"nodes\":[9,0,1,0,1,0,\n9,1,3,0,1,0,\n9,2,5,0,0,0]"));
+        // The root's first element, then a's edge named "b" to node 2
+        assert!(out.contains("\"edges\":[1,0,6,\n3,3,12]"));
+        assert!(out.contains("\"strings\":[\"\",\n\"a\",\n\"b\\\"\",\n\"b\"]"));
+    }
+}
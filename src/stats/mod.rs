@@ -0,0 +1,160 @@
+use std::cmp;
+use std::time::{Duration, Instant};
+
+use alloc::AllocBox;
+
+/// A snapshot of what the collector has done over the lifetime of a
+/// `ScopeManager`. Object counts are exact; byte counts are estimates based
+/// on the `ptr_size` of each object's data, as it was allocated and as it
+/// has grown or shrunk in place since.
+#[derive(Clone, Debug)]
+pub struct GcStats {
+    pub collections: usize,
+    pub total_pause: Duration,
+    pub max_pause: Duration,
+    pub objects_allocated: usize,
+    pub objects_freed: usize,
+    pub bytes_allocated: usize,
+    pub bytes_freed: usize,
+    pub live_objects: usize,
+    pub live_bytes: usize,
+    pub max_scope_depth: usize,
+}
+
+/// Keeps the running totals behind `GcStats` that the heap doesn't. The heap
+/// counts objects and bytes itself as they're allocated, mutated and freed,
+/// whichever way they got there, so this only tracks collections, pauses
+/// and scope depth, each in constant time.
+#[derive(Debug)]
+pub struct StatsTracker {
+    collections: usize,
+    total_pause: Duration,
+    max_pause: Duration,
+    max_scope_depth: usize,
+}
+
+impl Default for StatsTracker {
+    fn default() -> StatsTracker {
+        StatsTracker {
+            collections: 0,
+            total_pause: Duration::new(0, 0),
+            max_pause: Duration::new(0, 0),
+            max_scope_depth: 0,
+        }
+    }
+}
+
+impl StatsTracker {
+    pub fn new() -> StatsTracker {
+        StatsTracker::default()
+    }
+
+    #[inline]
+    pub fn record_scope_depth(&mut self, depth: usize) {
+        self.max_scope_depth = cmp::max(self.max_scope_depth, depth);
+    }
+
+    /// Record a pause that began at `start` and ends now.
+    pub fn record_pause(&mut self, start: Instant) {
+        let pause = start.elapsed();
+        self.total_pause = self.total_pause + pause;
+        self.max_pause = cmp::max(self.max_pause, pause);
+    }
+
+    #[inline]
+    pub fn record_collection(&mut self) {
+        self.collections += 1;
+    }
+
+    pub fn snapshot(&self, heap: &AllocBox) -> GcStats {
+        let objects_allocated = heap.objects_allocated();
+        let bytes_allocated = heap.bytes_allocated();
+        GcStats {
+            collections: self.collections,
+            total_pause: self.total_pause,
+            max_pause: self.max_pause,
+            objects_allocated: objects_allocated,
+            objects_freed: objects_allocated - heap.len(),
+            bytes_allocated: bytes_allocated,
+            bytes_freed: bytes_allocated - heap.bytes(),
+            live_objects: heap.len(),
+            live_bytes: heap.bytes(),
+            max_scope_depth: self.max_scope_depth,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc::{self, ptr_size};
+
+    use std::time::Instant;
+
+    use jsrs_common::test_utils;
+
+    #[test]
+    fn test_alloc_store_sweep() {
+        let heap = alloc::make_alloc_box();
+        let tracker = StatsTracker::new();
+        let (var, ptr) = test_utils::make_str("test");
+        let size = ptr_size(&ptr);
+        heap.lock().unwrap().alloc(var.unique.clone(), ptr).unwrap();
+
+        // Growing an object in place allocates, and shrinking it frees
+        let (_, longer) = test_utils::make_str("test!!");
+        let longer_size = ptr_size(&longer);
+        heap.lock().unwrap().update_ptr(&var.unique, longer).unwrap();
+        let (_, ptr) = test_utils::make_str("test");
+        heap.lock().unwrap().update_ptr(&var.unique, ptr).unwrap();
+        let stats = tracker.snapshot(&*heap.lock().unwrap());
+        assert_eq!(stats.objects_allocated, 1);
+        assert_eq!(stats.bytes_allocated, longer_size);
+        assert_eq!(stats.bytes_freed, longer_size - size);
+        assert_eq!(stats.live_bytes, size);
+
+        // Replacing it allocates a whole new object
+        let (_, ptr) = test_utils::make_str("other");
+        let other_size = ptr_size(&ptr);
+        heap.lock().unwrap().replace(var.unique.clone(), ptr).unwrap();
+        let stats = tracker.snapshot(&*heap.lock().unwrap());
+        assert_eq!(stats.objects_allocated, 2);
+        assert_eq!(stats.bytes_allocated, longer_size + other_size);
+
+        // A reference dropped while its object lives on through another one
+        // frees nothing
+        let alias = test_utils::make_num(0.).unique;
+        heap.lock().unwrap().alias(alias.clone(), &var.unique).unwrap();
+        heap.lock().unwrap().release(&var.unique).unwrap();
+        heap.lock().unwrap().mark_ptrs();
+        heap.lock().unwrap().sweep_ptrs();
+        let stats = tracker.snapshot(&*heap.lock().unwrap());
+        assert_eq!(stats.objects_freed, 1);
+        assert_eq!(stats.live_bytes, other_size);
+
+        heap.lock().unwrap().condemn(alias).unwrap();
+        heap.lock().unwrap().mark_ptrs();
+        heap.lock().unwrap().sweep_ptrs();
+        let stats = tracker.snapshot(&*heap.lock().unwrap());
+        assert_eq!(stats.objects_allocated, 2);
+        assert_eq!(stats.objects_freed, 2);
+        assert_eq!(stats.live_objects, 0);
+        assert_eq!(stats.live_bytes, 0);
+        assert_eq!(stats.bytes_freed, stats.bytes_allocated);
+    }
+
+    #[test]
+    fn test_pauses() {
+        let heap = alloc::make_alloc_box();
+        let mut tracker = StatsTracker::new();
+        tracker.record_pause(Instant::now());
+        tracker.record_pause(Instant::now());
+        tracker.record_scope_depth(3);
+        tracker.record_scope_depth(2);
+        let stats = tracker.snapshot(&*heap.lock().unwrap());
+        assert!(stats.max_pause <= stats.total_pause);
+        assert_eq!(stats.max_scope_depth, 3);
+    }
+}
@@ -0,0 +1,109 @@
+use std::fmt;
+
+use jsrs_common::types::js_var::JsKey;
+use jsrs_common::types::binding::{Binding, UniqueBinding};
+
+/// A broken invariant found by `ScopeManager::verify`. Each of these would
+/// otherwise surface later, and far from its cause, as a failed lookup or a
+/// cell collected out from under a live variable.
+/// location: The scope the problem was found in, e.g. "scope 2 (Block)".
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Violation {
+    /// A pointer variable on a scope's stack has no cell in the heap.
+    DanglingVar {
+        location: String,
+        binding: Binding,
+        unique: UniqueBinding,
+    },
+    /// A local binding maps to a unique binding that isn't on the scope's
+    /// stack.
+    DanglingLocal {
+        location: String,
+        binding: Binding,
+        unique: UniqueBinding,
+    },
+    /// A variable is on a scope's stack under a unique binding other than its
+    /// own.
+    MisfiledVar {
+        location: String,
+        key: UniqueBinding,
+        unique: UniqueBinding,
+    },
+    /// A property of a reachable object points at a cell that isn't in the
+    /// heap.
+    DanglingProperty {
+        object: UniqueBinding,
+        key: JsKey,
+        unique: UniqueBinding,
+    },
+    /// A value held by a finalizer or an ephemeron table has no cell in the
+    /// heap.
+    DanglingHeldValue {
+        location: String,
+        unique: UniqueBinding,
+    },
+    /// A value held by one of the embedder's handles has no cell in the heap.
+    DanglingHandle { unique: UniqueBinding },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Violation::DanglingVar { ref location, ref binding, ref unique } => {
+                write!(f,
+                       "{}: pointer variable {:?} ({:?}) has no cell in the heap",
+                       location,
+                       binding,
+                       unique)
+            }
+            Violation::DanglingLocal { ref location, ref binding, ref unique } => {
+                write!(f,
+                       "{}: local {:?} maps to {:?}, which isn't on the stack",
+                       location,
+                       binding,
+                       unique)
+            }
+            Violation::MisfiledVar { ref location, ref key, ref unique } => {
+                write!(f,
+                       "{}: variable {:?} is on the stack under {:?}",
+                       location,
+                       unique,
+                       key)
+            }
+            Violation::DanglingProperty { ref object, ref key, ref unique } => {
+                write!(f,
+                       "object {:?}: property {:?} points to {:?}, which has no cell in the heap",
+                       object,
+                       key,
+                       unique)
+            }
+            Violation::DanglingHeldValue { ref location, ref unique } => {
+                write!(f,
+                       "{}: held value {:?} has no cell in the heap",
+                       location,
+                       unique)
+            }
+            Violation::DanglingHandle { ref unique } => {
+                write!(f, "handles: value {:?} has no cell in the heap", unique)
+            }
+        }
+    }
+}
+
+/// The violations heap verification mode found after an operation.
+/// op: The operation they were found after, e.g. "store".
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VerifyFailure {
+    pub op: String,
+    pub violations: Vec<Violation>,
+}
+
+impl fmt::Display for VerifyFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "heap verification failed after {}:", self.op)?;
+        for violation in &self.violations {
+            write!(f, "\n  {}", violation)?;
+        }
+        Ok(())
+    }
+}
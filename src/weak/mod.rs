@@ -0,0 +1,329 @@
+use std::collections::hash_map::HashMap;
+use std::collections::hash_set::HashSet;
+
+use jsrs_common::gc_error::{GcError, Result};
+use jsrs_common::types::js_var::{JsPtrEnum, JsType, JsVar};
+use jsrs_common::types::binding::UniqueBinding;
+
+use alloc::{AllocBox, ObjectId};
+
+/// A reference to a heap object that does not keep the object alive. It
+/// refers to the object itself rather than to any one reference to it, so it
+/// lasts for as long as the object does, whichever variables refer to it in
+/// the meantime. Once the collector has found the object dead, the reference
+/// can no longer be dereferenced.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct WeakRef {
+    target: ObjectId,
+}
+
+impl WeakRef {
+    pub fn new(target: ObjectId) -> WeakRef {
+        WeakRef { target: target }
+    }
+
+    #[inline]
+    pub fn target(&self) -> ObjectId {
+        self.target
+    }
+}
+
+/// Identifies an ephemeron table owned by a `ScopeManager`.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub struct EphemeronId(pub usize);
+
+/// A value held on behalf of the embedder outside of any scope, e.g. by an
+/// ephemeron table or a finalizer registration. A pointer value is held
+/// through a heap reference of its own, which is dropped when the value is
+/// released.
+#[derive(Clone, Debug)]
+pub struct HeldValue {
+    var: JsVar,
+}
+
+impl HeldValue {
+    /// Hold a value, allocating its pointer in the heap if it isn't there yet.
+    pub fn new(var: JsVar, ptr: Option<JsPtrEnum>, heap: &mut AllocBox) -> Result<HeldValue> {
+        let mut var = var;
+        match var.t {
+            JsType::JsPtr(_) => {
+                if heap.is_allocated(&var.unique) {
+                    // The value gets a reference of its own, so that it
+                    // doesn't change along with the variable it came from.
+                    let alias = JsVar::new(var.t.clone());
+                    heap.alias(alias.unique.clone(), &var.unique)?;
+                    var = alias;
+                } else if let Some(ptr) = ptr {
+                    heap.alloc(var.unique.clone(), ptr)?;
+                } else {
+                    return Err(GcError::PtrAlloc);
+                }
+            }
+            _ => {
+                if ptr.is_some() {
+                    return Err(GcError::PtrAlloc);
+                }
+            }
+        }
+        Ok(HeldValue { var: var })
+    }
+
+    /// The unique binding of the value's heap cell, if it has one.
+    pub fn ptr_unique(&self) -> Option<&UniqueBinding> {
+        match self.var.t {
+            JsType::JsPtr(_) => Some(&self.var.unique),
+            _ => None,
+        }
+    }
+
+    /// Return a copy of the value and its pointer data.
+    pub fn copy(&self, heap: &AllocBox) -> (JsVar, Option<JsPtrEnum>) {
+        let ptr = self.ptr_unique()
+                      .and_then(|unique| heap.find_id(unique))
+                      .map(|alloc| alloc.borrow().clone());
+        (self.var.clone(), ptr)
+    }
+
+    /// Stop holding the value, dropping its heap reference. The object stays
+    /// in the heap for as long as anything else refers to it.
+    pub fn release(self, heap: &mut AllocBox) {
+        if let Some(unique) = self.ptr_unique() {
+            heap.release(unique).ok();
+        }
+    }
+}
+
+/// A table of key/value pairs where the keys are held weakly, and each value
+/// is only kept alive for as long as its key is reachable from somewhere
+/// other than the table. This is the primitive `WeakMap` and `WeakSet` are
+/// built on. Keys are objects, so an entry can be found through any
+/// reference to its key.
+#[derive(Debug, Default)]
+pub struct EphemeronTable {
+    entries: HashMap<ObjectId, HeldValue>,
+    cleared: Vec<WeakRef>,
+}
+
+impl EphemeronTable {
+    pub fn new() -> EphemeronTable {
+        EphemeronTable {
+            entries: HashMap::new(),
+            cleared: Vec::new(),
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    #[inline]
+    pub fn has(&self, key: &WeakRef) -> bool {
+        self.entries.contains_key(&key.target())
+    }
+
+    /// Associate a value with a key, replacing any previous value. Pointer
+    /// values that aren't in the heap yet are allocated there.
+    pub fn set(&mut self,
+               key: &WeakRef,
+               value: JsVar,
+               ptr: Option<JsPtrEnum>,
+               heap: &mut AllocBox)
+               -> Result<()> {
+        if !heap.is_live(key.target()) {
+            return Err(GcError::PtrAlloc);
+        }
+        let value = HeldValue::new(value, ptr, heap)?;
+        if let Some(old) = self.entries.insert(key.target(), value) {
+            old.release(heap);
+        }
+        Ok(())
+    }
+
+    /// The unique binding of the heap reference through which the table holds
+    /// a key's value, if the value is a pointer.
+    pub fn value_unique(&self, key: &WeakRef) -> Option<&UniqueBinding> {
+        self.entries.get(&key.target()).and_then(HeldValue::ptr_unique)
+    }
+
+    /// Return a copy of the value associated with a key.
+    pub fn get(&self, key: &WeakRef, heap: &AllocBox) -> Option<(JsVar, Option<JsPtrEnum>)> {
+        self.entries.get(&key.target()).map(|value| value.copy(heap))
+    }
+
+    /// Remove a key and its value from the table, returning whether it was present.
+    pub fn delete(&mut self, key: &WeakRef, heap: &mut AllocBox) -> bool {
+        if let Some(value) = self.entries.remove(&key.target()) {
+            value.release(heap);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Drain the keys whose entries were cleared by the collector since the
+    /// last call.
+    pub fn take_cleared(&mut self) -> Vec<WeakRef> {
+        self.cleared.drain(..).collect()
+    }
+
+    /// Remove every entry, releasing any values the table owns.
+    pub fn clear(&mut self, heap: &mut AllocBox) {
+        for (_, value) in self.entries.drain() {
+            value.release(heap);
+        }
+    }
+
+    /// The heap-allocated values whose keys have been marked, and which
+    /// therefore have to be marked themselves.
+    pub fn live_values(&self, marked: &HashSet<ObjectId>) -> Vec<UniqueBinding> {
+        self.entries
+            .iter()
+            .filter(|&(key, _)| marked.contains(key))
+            .filter_map(|(_, value)| value.ptr_unique().cloned())
+            .collect()
+    }
+
+    /// Every (key, value) pair whose value is heap-allocated.
+    pub fn value_edges(&self) -> Vec<(ObjectId, UniqueBinding)> {
+        self.entries
+            .iter()
+            .filter_map(|(key, value)| value.ptr_unique().map(|v| (*key, v.clone())))
+            .collect()
+    }
+
+    /// Clear every entry whose key wasn't marked, releasing the values the
+    /// table owns so the sweep that follows can reclaim them.
+    pub fn clear_dead(&mut self, marked: &HashSet<ObjectId>, heap: &mut AllocBox) {
+        let dead: Vec<_> = self.entries
+                               .keys()
+                               .filter(|key| !marked.contains(*key))
+                               .cloned()
+                               .collect();
+        for key in dead {
+            if let Some(value) = self.entries.remove(&key) {
+                value.release(heap);
+            }
+            self.cleared.push(WeakRef::new(key));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc;
+
+    use std::collections::hash_set::HashSet;
+    use std::sync::{Arc, Mutex};
+
+    use jsrs_common::test_utils;
+
+    fn make_key() -> (WeakRef, Arc<Mutex<AllocBox>>) {
+        let heap = alloc::make_alloc_box();
+        let (var, ptr) = test_utils::make_str("key");
+        heap.lock().unwrap().alloc(var.unique.clone(), ptr).unwrap();
+        let id = heap.lock().unwrap().object_id(&var.unique).unwrap();
+        (WeakRef::new(id), heap)
+    }
+
+    #[test]
+    fn test_set_get() {
+        let (key, heap) = make_key();
+        let mut table = EphemeronTable::new();
+        table.set(&key, test_utils::make_num(1.), None, &mut *heap.lock().unwrap()).unwrap();
+        assert!(table.has(&key));
+        let (var, ptr) = table.get(&key, &*heap.lock().unwrap()).unwrap();
+        assert!(matches!(var.t, JsType::JsNum(_)));
+        assert!(ptr.is_none());
+    }
+
+    #[test]
+    fn test_set_allocates_value() {
+        let (key, heap) = make_key();
+        let mut table = EphemeronTable::new();
+        let (var, ptr) = test_utils::make_str("value");
+        table.set(&key, var, Some(ptr), &mut *heap.lock().unwrap()).unwrap();
+        assert_eq!(heap.lock().unwrap().len(), 2);
+        let (_, ptr) = table.get(&key, &*heap.lock().unwrap()).unwrap();
+        assert!(ptr.is_some());
+    }
+
+    #[test]
+    fn test_set_holds_allocated_value() {
+        let (key, heap) = make_key();
+        let mut table = EphemeronTable::new();
+        let (var, ptr) = test_utils::make_str("value");
+        heap.lock().unwrap().alloc(var.unique.clone(), ptr).unwrap();
+        table.set(&key, var.clone(), None, &mut *heap.lock().unwrap()).unwrap();
+
+        // The table keeps the value alive once its variable lets go of it
+        heap.lock().unwrap().release(&var.unique).unwrap();
+        heap.lock().unwrap().mark_ptrs();
+        heap.lock().unwrap().sweep_ptrs();
+        let (held, ptr) = table.get(&key, &*heap.lock().unwrap()).unwrap();
+        assert!(held.unique != var.unique);
+        assert!(ptr.is_some());
+
+        // And lets go of it when the entry is deleted
+        assert!(table.delete(&key, &mut *heap.lock().unwrap()));
+        heap.lock().unwrap().mark_ptrs();
+        heap.lock().unwrap().sweep_ptrs();
+        assert_eq!(heap.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_set_dead_key_fail() {
+        let heap = alloc::make_alloc_box();
+        let (var, ptr) = test_utils::make_str("key");
+        heap.lock().unwrap().alloc(var.unique.clone(), ptr).unwrap();
+        let key = WeakRef::new(heap.lock().unwrap().object_id(&var.unique).unwrap());
+        heap.lock().unwrap().condemn(var.unique).unwrap();
+        heap.lock().unwrap().mark_ptrs();
+        heap.lock().unwrap().sweep_ptrs();
+        let mut table = EphemeronTable::new();
+        let res = table.set(&key,
+                            test_utils::make_num(1.),
+                            None,
+                            &mut *heap.lock().unwrap());
+        assert!(matches!(res, Err(GcError::PtrAlloc)));
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn test_delete() {
+        let (key, heap) = make_key();
+        let mut table = EphemeronTable::new();
+        table.set(&key, test_utils::make_num(1.), None, &mut *heap.lock().unwrap()).unwrap();
+        assert!(table.delete(&key, &mut *heap.lock().unwrap()));
+        assert!(!table.delete(&key, &mut *heap.lock().unwrap()));
+        assert!(table.take_cleared().is_empty());
+    }
+
+    #[test]
+    fn test_clear_dead() {
+        let (key, heap) = make_key();
+        let mut table = EphemeronTable::new();
+        let (var, ptr) = test_utils::make_str("value");
+        let value_unique = var.unique.clone();
+        table.set(&key, var, Some(ptr), &mut *heap.lock().unwrap()).unwrap();
+
+        let mut marked = HashSet::new();
+        marked.insert(key.target());
+        assert_eq!(table.live_values(&marked), vec![value_unique]);
+        table.clear_dead(&marked, &mut *heap.lock().unwrap());
+        assert_eq!(table.len(), 1);
+
+        table.clear_dead(&HashSet::new(), &mut *heap.lock().unwrap());
+        assert!(table.is_empty());
+        assert_eq!(table.take_cleared(), vec![key]);
+        assert!(table.take_cleared().is_empty());
+    }
+}